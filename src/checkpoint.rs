@@ -0,0 +1,348 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write},
+    path::Path,
+};
+
+use ndarray::{Array1, Array2};
+
+use crate::{
+    config::*,
+    healpix::Ordering,
+    particles::{ParticleType, Particles},
+    sky_map::Shells,
+};
+
+const MAGIC: &[u8; 8] = b"NBODYCK2";
+
+/// Everything the main loop needs to carry on from the end of a step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub particles: Particles,
+    /// Scale factor reached
+    pub time: f64,
+    /// Steps taken so far
+    pub step: usize,
+    /// Snapshots written so far
+    pub plots: usize,
+    /// State of the IC generator; stepping draws no random numbers, so the white noise
+    /// seed is all there is
+    pub seed: u64,
+    /// `config_summary` of the run that wrote the checkpoint
+    pub config: String,
+    /// Sky maps of the lightcone accumulated so far, if there is an observer
+    pub shells: Option<Shells>,
+}
+
+/// The compile-time parameters that change the trajectory, one `NAME = value` line
+/// each, so that a restart with a different build can be refused.
+pub fn config_summary() -> String {
+    [
+        ("N_PARTICLES", N_PARTICLES.to_string()),
+        ("N_CELLS", N_CELLS.to_string()),
+        ("BOX_SIZE", BOX_SIZE.to_string()),
+        ("OMEGA_M0", OMEGA_M0.to_string()),
+        ("OMEGA_B0", OMEGA_B0.to_string()),
+        ("OMEGA_K0", OMEGA_K0.to_string()),
+        ("OMEGA_LAMBDA0", OMEGA_LAMBDA0.to_string()),
+        ("H0", H0.to_string()),
+        ("A_INIT", A_INIT.to_string()),
+        ("A_END", A_END.to_string()),
+        ("STEPS", STEPS.to_string()),
+        ("OUTPUT_TIMES", format!("{:?}", OUTPUT_TIMES)),
+        ("SORT_CURVE", format!("{:?}", SORT_CURVE)),
+        ("SORT_EVERY", SORT_EVERY.to_string()),
+        ("PRECISION", format!("{:?}", PRECISION)),
+        ("LIGHTCONE_OBSERVER", format!("{:?}", LIGHTCONE_OBSERVER)),
+        ("LIGHTCONE_Z_MAX", LIGHTCONE_Z_MAX.to_string()),
+        ("SKY_MAP_NSIDE", SKY_MAP_NSIDE.to_string()),
+        ("SKY_MAP_SHELLS", format!("{:?}", SKY_MAP_SHELLS)),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{} = {}\n", name, value))
+    .collect()
+}
+
+/// Writes `checkpoint` next to `path` and renames it into place, so a run killed
+/// mid-write leaves the previous checkpoint intact.
+pub fn write_checkpoint(path: impl AsRef<Path>, checkpoint: &Checkpoint) -> Result<()> {
+    let path = path.as_ref();
+    let partial = path.with_extension("partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    let particles = &checkpoint.particles;
+
+    out.write_all(MAGIC)?;
+    write_u64(&mut out, checkpoint.config.len() as u64)?;
+    out.write_all(checkpoint.config.as_bytes())?;
+    write_f64(&mut out, checkpoint.time)?;
+    write_u64(&mut out, checkpoint.step as u64)?;
+    write_u64(&mut out, checkpoint.plots as u64)?;
+    write_u64(&mut out, checkpoint.seed)?;
+    write_u64(&mut out, particles.len() as u64)?;
+    for id in &particles.ids {
+        write_u64(&mut out, *id)?;
+    }
+    for x in particles
+        .positions
+        .iter()
+        .chain(&particles.velocities)
+        .chain(&particles.masses)
+    {
+        write_f64(&mut out, *x)?;
+    }
+    let types: Vec<u8> = particles.types.iter().map(|t| t.code()).collect();
+    out.write_all(&types)?;
+    write_shells(&mut out, checkpoint.shells.as_ref())?;
+
+    out.into_inner()?.sync_all()?;
+    fs::rename(partial, path)
+}
+
+pub fn read_checkpoint(path: impl AsRef<Path>) -> Result<Checkpoint> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a checkpoint file"));
+    }
+    let mut config = vec![0; read_u64(&mut input)? as usize];
+    input.read_exact(&mut config)?;
+    let config = String::from_utf8(config).map_err(|e| invalid(&e.to_string()))?;
+    let time = read_f64(&mut input)?;
+    let step = read_u64(&mut input)? as usize;
+    let plots = read_u64(&mut input)? as usize;
+    let seed = read_u64(&mut input)?;
+
+    let n = read_u64(&mut input)? as usize;
+    let ids = (0..n)
+        .map(|_| read_u64(&mut input))
+        .collect::<Result<_>>()?;
+    let mut floats = |len| -> Result<Vec<f64>> { (0..len).map(|_| read_f64(&mut input)).collect() };
+    let positions = Array2::from_shape_vec((3, n), floats(3 * n)?).unwrap();
+    let velocities = Array2::from_shape_vec((3, n), floats(3 * n)?).unwrap();
+    let masses = Array1::from(floats(n)?);
+    let mut types = vec![0; n];
+    input.read_exact(&mut types)?;
+    let types = types
+        .into_iter()
+        .map(|code| {
+            ParticleType::from_code(code)
+                .ok_or_else(|| invalid(&format!("unknown particle type {}", code)))
+        })
+        .collect::<Result<_>>()?;
+    let shells = read_shells(&mut input)?;
+
+    Ok(Checkpoint {
+        particles: Particles {
+            ids: Array1::from_vec(ids),
+            positions,
+            velocities,
+            masses,
+            types,
+        },
+        time,
+        step,
+        plots,
+        seed,
+        config,
+        shells,
+    })
+}
+
+/// Reads a checkpoint and refuses it unless it was written with the current
+/// configuration.
+pub fn restart(path: impl AsRef<Path>) -> Result<Checkpoint> {
+    let checkpoint = read_checkpoint(path)?;
+    if checkpoint.config != config_summary() || checkpoint.seed != SEED {
+        return Err(invalid(&format!(
+            "checkpoint written with a different configuration:\n{}",
+            checkpoint.config
+        )));
+    }
+    Ok(checkpoint)
+}
+
+// A presence byte, then the observer, the redshift edges, the map resolution and
+// ordering, and the pixels of every map in turn.
+fn write_shells(out: &mut impl Write, shells: Option<&Shells>) -> Result<()> {
+    let Some(shells) = shells else {
+        return out.write_all(&[0]);
+    };
+    out.write_all(&[1])?;
+    for x in shells.observer {
+        write_f64(out, x)?;
+    }
+    write_u64(out, shells.edges.len() as u64)?;
+    for x in &shells.edges {
+        write_f64(out, *x)?;
+    }
+    let (nside, ordering) = shells
+        .maps
+        .first()
+        .map_or((0, Ordering::Ring), |map| (map.nside, map.ordering));
+    write_u64(out, nside as u64)?;
+    out.write_all(&[match ordering {
+        Ordering::Ring => 0,
+        Ordering::Nested => 1,
+    }])?;
+    for x in shells.maps.iter().flat_map(|map| &map.pixels) {
+        write_f64(out, *x)?;
+    }
+    Ok(())
+}
+
+fn read_shells(input: &mut impl Read) -> Result<Option<Shells>> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    if byte[0] == 0 {
+        return Ok(None);
+    }
+    let observer = [read_f64(input)?, read_f64(input)?, read_f64(input)?];
+    let n_edges = read_u64(input)? as usize;
+    let edges = (0..n_edges)
+        .map(|_| read_f64(input))
+        .collect::<Result<Vec<_>>>()?;
+    let nside = read_u64(input)? as usize;
+    input.read_exact(&mut byte)?;
+    let ordering = match byte[0] {
+        0 => Ordering::Ring,
+        1 => Ordering::Nested,
+        code => return Err(invalid(&format!("unknown pixel ordering {}", code))),
+    };
+    let mut shells = Shells::new(observer, &edges, nside, ordering);
+    for map in &mut shells.maps {
+        for x in &mut map.pixels {
+            *x = read_f64(input)?;
+        }
+    }
+    Ok(Some(shells))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn write_u64(out: &mut impl Write, x: u64) -> Result<()> {
+    out.write_all(&x.to_le_bytes())
+}
+
+fn write_f64(out: &mut impl Write, x: f64) -> Result<()> {
+    out.write_all(&x.to_le_bytes())
+}
+
+fn read_u64(input: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(input: &mut impl Read) -> Result<f64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        float::Float,
+        fourier::ksq_inv,
+        ic::{initial_conditions, LptOrder, ParticleLoad},
+        integrate::update,
+    };
+    use ndarray::Array3;
+
+    fn step<F: Float>(particles: &mut Particles<F>, fgrid: &Array3<F>, t: f64, dt: f64) {
+        let positions = particles.positions.clone();
+        let velocities = particles.velocities.clone();
+        (particles.positions, particles.velocities) =
+            update(particles.density(), positions, velocities, fgrid, t, dt);
+    }
+
+    #[test]
+    fn restart_is_bit_identical() {
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, k)| {
+            0.05 * ((i + 2 * j + 3 * k) as f64).sin()
+        });
+        let mut particles = initial_conditions(field, LptOrder::Second, ParticleLoad::Lattice);
+        particles.types[0] = ParticleType::Boundary;
+        let fgrid = ksq_inv();
+        let (mut t, dt) = (0.1, 0.01);
+        for _ in 0..3 {
+            step(&mut particles, &fgrid, t, dt);
+            t += dt;
+        }
+
+        let mut shells = Shells::new([5.; 3], &[0., 0.1, 0.3], 2, Ordering::Nested);
+        shells.maps[1].add([1., -2., 0.5], 2.5);
+
+        let path = std::env::temp_dir().join("nbody_test_checkpoint.bin");
+        let checkpoint = Checkpoint {
+            particles: particles.clone(),
+            time: t,
+            step: 3,
+            plots: 1,
+            seed: SEED,
+            config: config_summary(),
+            shells: Some(shells),
+        };
+        write_checkpoint(&path, &checkpoint).unwrap();
+        let mut resumed = restart(&path).unwrap();
+        assert_eq!(resumed, checkpoint);
+
+        for _ in 0..3 {
+            step(&mut particles, &fgrid, t, dt);
+            step(&mut resumed.particles, &fgrid, resumed.time, dt);
+            t += dt;
+            resumed.time += dt;
+        }
+        assert_eq!(resumed.particles, particles);
+
+        write_checkpoint(
+            &path,
+            &Checkpoint {
+                config: "N_CELLS = 1\n".to_string(),
+                ..checkpoint
+            },
+        )
+        .unwrap();
+        assert!(restart(&path).is_err());
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn single_precision_restart_is_bit_identical() {
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, k)| {
+            0.05 * ((3 * i + j + 2 * k) as f64).cos()
+        });
+        let mut particles: Particles<f32> =
+            initial_conditions(field, LptOrder::First, ParticleLoad::Lattice).cast();
+        let fgrid = ksq_inv().mapv(f32::of);
+        let (mut t, dt) = (0.1, 0.01);
+        step(&mut particles, &fgrid, t, dt);
+        t += dt;
+
+        let path = std::env::temp_dir().join("nbody_test_checkpoint_f32.bin");
+        let checkpoint = Checkpoint {
+            particles: particles.cast(),
+            time: t,
+            step: 1,
+            plots: 0,
+            seed: SEED,
+            config: config_summary(),
+            shells: None,
+        };
+        write_checkpoint(&path, &checkpoint).unwrap();
+        let mut resumed: Particles<f32> = restart(&path).unwrap().particles.cast();
+        assert_eq!(resumed, particles);
+
+        for _ in 0..3 {
+            step(&mut particles, &fgrid, t, dt);
+            step(&mut resumed, &fgrid, t, dt);
+            t += dt;
+        }
+        assert_eq!(resumed, particles);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,5 +1,17 @@
+use crate::{
+    constrained::Constraint,
+    curve::SpaceFillingCurve,
+    float::Precision,
+    gadget::GadgetFormat,
+    ic::{LptOrder, ParticleLoad},
+    power_spectrum::Transfer,
+    schedule::OutputTime::{self, Redshift, ScaleFactor},
+    zoom::ZoomRegion,
+};
+
 pub const N_PARTICLES: usize = 10;
 pub const N_CELLS: usize = 16;
+pub const N_FIELD: usize = N_PARTICLES; // Cells per side of the IC density field
 pub const BOX_SIZE: usize = 5;
 
 // pub const N_PARTICLES: usize = 6;
@@ -12,13 +24,50 @@ pub const OMEGA_B0: f64 = 0.04;
 pub const OMEGA_K0: f64 = 0.00;
 pub const OMEGA_LAMBDA0: f64 = 0.69;
 pub const H0: f64 = 0.68;
+pub const N_S: f64 = 0.965; // Primordial spectral index
+pub const T_CMB: f64 = 2.7255; // CMB temperature in K
 pub const A_INIT: f64 = 0.01; // Initial Growth Factor
 pub const A_END: f64 = 100.00; // Final scale factor
 pub const STEPS: f64 = 1000.; // Number of timesteps
-pub const N_PLOTS: f64 = 100.; // Number of timesteps
+pub const OUTPUT_TIMES: &[OutputTime] = &[
+    Redshift(9.),
+    Redshift(3.),
+    Redshift(1.),
+    Redshift(0.),
+    ScaleFactor(A_END),
+]; // Snapshots, each landed on exactly by the integrator
 
 pub const DIV_BY_ZERO: f64 = 1e-34;
 pub const IMG_WIDTH: usize = N_CELLS.pow(2);
 
-pub const POWER: f64 = 0.845;
-pub const AMPLITUDE: f64 = 3.685;
+pub const TRANSFER: Transfer = Transfer::EisensteinHu;
+pub const LPT_ORDER: LptOrder = LptOrder::Second;
+pub const PARTICLE_LOAD: ParticleLoad = ParticleLoad::Lattice;
+pub const GLASS_STEPS: usize = 100; // Relaxation steps for ParticleLoad::Glass
+pub const POWER_SPECTRUM_FILE: Option<&str> = None; // CAMB *_matterpower.dat or CLASS *_pk.dat
+pub const TRANSFER_FILE: Option<&str> = None; // CAMB *_transfer_out.dat, with N_S and SIGMA_8 instead of TRANSFER
+pub const SEED: u64 = 42; // Seed of the initial white noise
+pub const FIXED_AMPLITUDE: bool = false; // Random phases only, |delta_k|^2 = P(k)
+pub const PAIRED: bool = false; // Phase-flipped partner of the SEED realization
+pub const F_NL: f64 = 0.; // Local primordial non-Gaussianity
+pub const CONSTRAINTS: &[Constraint] = &[]; // Hoffman-Ribak constraints on the initial field
+pub const SIGMA_8: f64 = 0.81; // Linear RMS fluctuation in 8 Mpc/h spheres at a = 1
+pub const ZOOM_REGION: Option<ZoomRegion> = None; // Lagrangian region kept at full lattice resolution, in cells; lattice loads only
+pub const ZOOM_LEVELS: usize = 2; // Coarsening levels around ZOOM_REGION, 8x heavier each
+pub const ZOOM_SHELL_WIDTH: f64 = 2.; // Thickness of each coarsening shell, in cells
+pub const TWO_SPECIES: bool = false; // Separate CDM and baryon particles from the Eisenstein-Hu transfers
+pub const WDM_MASS: Option<f64> = None; // Thermal relic mass in keV, truncating the spectrum
+pub const NEUTRINO_MASS: Option<f64> = None; // Mass in eV of one species of neutrino particles
+pub const PRECISION: Precision = Precision::Double; // Storage of particles and meshes while stepping
+pub const THREADS: usize = 0; // Rayon worker threads for deposit and force gather, 0 for all cores
+pub const SORT_CURVE: SpaceFillingCurve = SpaceFillingCurve::Hilbert; // Particle memory order
+pub const SORT_EVERY: usize = 20; // Steps between re-sorting the particles, 0 to never sort
+pub const CHECKPOINT_FILE: &str = "./checkpoint.bin"; // Resumed from with --restart
+pub const CHECKPOINT_EVERY: usize = 100; // Steps between checkpoints, 0 to never write one
+pub const GADGET_FORMAT: Option<GadgetFormat> = None; // Also write GADGET-2 snapshots at every plot
+pub const NPZ_OUTPUT: bool = false; // Also write density, potential and particles as .npz at every plot
+pub const VTK_OUTPUT: bool = false; // Also write particles (.vtp) and mesh fields (.vti) at every plot
+pub const LIGHTCONE_OBSERVER: Option<[f64; 3]> = None; // Observer in cells for lightcone shells
+pub const LIGHTCONE_Z_MAX: f64 = 0.5; // Depth of the lightcone
+pub const SKY_MAP_NSIDE: usize = 64; // HEALPix resolution of the lightcone sky maps
+pub const SKY_MAP_SHELLS: &[f64] = &[0., 0.1, 0.2, 0.3, 0.5]; // Redshift edges of the sky map shells
@@ -0,0 +1,199 @@
+use ndarray::{Array2, Array3, Zip};
+use rustfft::num_complex::Complex64;
+
+use crate::{
+    config::{A_INIT, BOX_SIZE},
+    cosmology::growth_factor,
+    fourier::{forward, inverse},
+    ic::wavevectors,
+    power_spectrum::PowerSpectrum,
+    random_field::{gaussian_random_field, RandomFieldOptions},
+};
+
+/// Linear functional of the Gaussian-smoothed density field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstraintKind {
+    /// delta
+    Density,
+    /// d delta / dx_a, in h/Mpc
+    Gradient(usize),
+    /// Traceless tidal tensor (d_a d_b / laplace - delta_ab / 3) delta
+    Shear(usize, usize),
+}
+
+/// Constraint on the linear density field at a = 1, smoothed with a Gaussian of
+/// `radius` Mpc/h and evaluated at `position` in Mpc/h from the first grid node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Constraint {
+    pub kind: ConstraintKind,
+    pub position: [f64; 3],
+    pub radius: f64,
+    pub value: f64,
+}
+
+impl Constraint {
+    // g(k) such that C[delta] = sum_k g(k) delta_k / N^3
+    fn kernel(&self, k: &[Array3<f64>; 3]) -> Array3<Complex64> {
+        let mut kernel: Array3<Complex64> = Array3::zeros(k[0].dim());
+        Zip::from(&mut kernel)
+            .and(&k[0])
+            .and(&k[1])
+            .and(&k[2])
+            .for_each(|g, kx, ky, kz| {
+                let kv = [*kx, *ky, *kz];
+                let ksq: f64 = kv.iter().map(|x| x * x).sum();
+                let phase: f64 = kv.iter().zip(self.position).map(|(k, x)| k * x).sum();
+                let window = (-ksq * self.radius.powi(2) / 2.).exp();
+                let op = match self.kind {
+                    ConstraintKind::Density => Complex64::new(1., 0.),
+                    ConstraintKind::Gradient(a) => Complex64::new(0., kv[a]),
+                    ConstraintKind::Shear(a, b) if ksq > 0. => {
+                        let trace = if a == b { 1. / 3. } else { 0. };
+                        Complex64::new(kv[a] * kv[b] / ksq - trace, 0.)
+                    }
+                    ConstraintKind::Shear(..) => Complex64::new(0., 0.),
+                };
+                *g = op * window * Complex64::from_polar(1., phase);
+            });
+        kernel
+    }
+}
+
+fn evaluate(kernel: &Array3<Complex64>, density_k: &Array3<Complex64>) -> f64 {
+    let n = kernel.len() as f64;
+    Zip::from(kernel)
+        .and(density_k)
+        .fold(Complex64::new(0., 0.), |acc, g, d| acc + g * d)
+        .re
+        / n
+}
+
+/// Hoffman & Ribak (1991) constrained realization: an unconstrained field from
+/// `gaussian_random_field` is corrected by the mean field of the residuals,
+/// delta = delta_u + <delta C_i> <C_i C_j>^-1 (c_j - C_j[delta_u]), which satisfies
+/// every constraint exactly and keeps the statistics of P(k).
+pub fn constrained_random_field(
+    spectrum: &PowerSpectrum,
+    options: &RandomFieldOptions,
+    constraints: &[Constraint],
+) -> Array3<f64> {
+    let field: Array3<f64> = gaussian_random_field(spectrum, options);
+    if constraints.is_empty() {
+        return field;
+    }
+
+    let growth = growth_factor(A_INIT);
+    let density_k: Array3<Complex64> = forward(&field.map(|x| Complex64::new(x / growth, 0.)));
+
+    // <|delta_k|^2> in the unnormalized DFT convention
+    let k: [Array3<f64>; 3] = wavevectors(options.grid);
+    let n = options.grid.pow(3) as f64;
+    let volume = (BOX_SIZE as f64).powi(3);
+    let variance_k: Array3<f64> =
+        Zip::from(&k[0])
+            .and(&k[1])
+            .and(&k[2])
+            .map_collect(|kx, ky, kz| {
+                n.powi(2) * spectrum.power((kx * kx + ky * ky + kz * kz).sqrt()) / volume
+            });
+
+    let kernels: Vec<Array3<Complex64>> = constraints.iter().map(|c| c.kernel(&k)).collect();
+    let covariance: Array2<f64> =
+        Array2::from_shape_fn((constraints.len(), constraints.len()), |(i, j)| {
+            Zip::from(&kernels[i])
+                .and(&kernels[j])
+                .and(&variance_k)
+                .fold(0., |acc, a, b, s| acc + (a * b.conj()).re * s)
+                / n.powi(2)
+        });
+    let residuals: Vec<f64> = constraints
+        .iter()
+        .zip(&kernels)
+        .map(|(c, g)| c.value - evaluate(g, &density_k))
+        .collect();
+    let weights: Vec<f64> = solve(covariance, residuals);
+
+    let mut constrained_k: Array3<Complex64> = density_k;
+    for (g, w) in kernels.iter().zip(weights) {
+        Zip::from(&mut constrained_k)
+            .and(g)
+            .and(&variance_k)
+            .for_each(|d, g, s| *d += g.conj() * s * w / n);
+    }
+    inverse(&constrained_k).map(|x| growth * x.re)
+}
+
+// Gaussian elimination with partial pivoting for the small constraint covariance.
+fn solve(mut a: Array2<f64>, mut b: Vec<f64>) -> Vec<f64> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|i, j| a[[*i, col]].abs().total_cmp(&a[[*j, col]].abs()))
+            .unwrap();
+        assert!(
+            a[[pivot, col]].abs() > 0.,
+            "constraints are degenerate, e.g. repeated or unsupported by P(k)"
+        );
+        for j in 0..n {
+            a.swap([col, j], [pivot, j]);
+        }
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let factor = a[[row, col]] / a[[col, col]];
+            for j in col..n {
+                a[[row, j]] -= factor * a[[col, j]];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = vec![0.; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|j| a[[row, j]] * x[j]).sum();
+        x[row] = (b[row] - sum) / a[[row, row]];
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::N_FIELD, power_spectrum::Transfer};
+
+    #[test]
+    fn constraints_are_satisfied() {
+        let spectrum = PowerSpectrum::new(Transfer::EisensteinHu);
+        let centre = [BOX_SIZE as f64 / 2.; 3];
+        let constraint = |kind, value| Constraint {
+            kind,
+            position: centre,
+            radius: 0.8,
+            value,
+        };
+        let constraints = [
+            constraint(ConstraintKind::Density, 3.),
+            constraint(ConstraintKind::Gradient(0), 0.),
+            constraint(ConstraintKind::Gradient(1), 0.),
+            constraint(ConstraintKind::Gradient(2), 0.),
+            constraint(ConstraintKind::Shear(0, 0), 0.2),
+            constraint(ConstraintKind::Shear(0, 1), -0.1),
+        ];
+        let field =
+            constrained_random_field(&spectrum, &RandomFieldOptions::default(), &constraints);
+
+        let growth = growth_factor(A_INIT);
+        let field_k = forward(&field.map(|x| Complex64::new(x / growth, 0.)));
+        let k = wavevectors(N_FIELD);
+        for c in constraints {
+            let measured = evaluate(&c.kernel(&k), &field_k);
+            assert!((measured - c.value).abs() < 1e-8, "{:?}: {}", c, measured);
+        }
+    }
+
+    #[test]
+    fn solves_linear_system() {
+        let a = Array2::from_shape_vec((3, 3), vec![0., 2., 1., 1., 1., 0., 3., 0., 1.]).unwrap();
+        let x = solve(a.clone(), vec![5., 3., 6.]);
+        let b = a.dot(&ndarray::Array1::from(x));
+        assert!((b[0] - 5.).abs() + (b[1] - 3.).abs() + (b[2] - 6.).abs() < 1e-12);
+    }
+}
@@ -1,9 +1,57 @@
-use crate::config::{H0, OMEGA_K0, OMEGA_LAMBDA0, OMEGA_M0};
+use crate::config::{H0, OMEGA_K0, OMEGA_LAMBDA0, OMEGA_M0, T_CMB};
+
+/// c / H0 in Mpc/h
+pub const HUBBLE_DISTANCE: f64 = 2997.92458;
+
+/// Critical density today in (M_sun/h) / (Mpc/h)^3
+pub const RHO_CRIT: f64 = 2.775e11;
+
+/// Speed of light in km/s
+pub const SPEED_OF_LIGHT: f64 = 299792.458;
+
+/// Boltzmann constant in eV/K
+const BOLTZMANN: f64 = 8.617333262e-5;
+
+/// k_B T of the relic neutrinos today in eV, with T_nu = (4/11)^(1/3) T_CMB.
+pub fn neutrino_temperature() -> f64 {
+    BOLTZMANN * (4f64 / 11.).cbrt() * T_CMB
+}
+
+/// Fraction of the matter density in one neutrino species of `mass` eV.
+pub fn neutrino_fraction(mass: f64) -> f64 {
+    mass / (93.14 * H0.powi(2)) / OMEGA_M0
+}
+
+/// Neutrino free-streaming wavenumber in h/Mpc at `a` for `mass` eV,
+/// k_fs = 0.82 a^2 E(a) m / 1 eV (Lesgourgues & Pastor 2006).
+pub fn free_streaming_wavenumber(mass: f64, a: f64) -> f64 {
+    0.82 * a.powi(2) * e_squared(a).sqrt() * mass
+}
 
 pub fn expansion_factor(t: f64) -> f64 {
     1. / ((OMEGA_M0 + OMEGA_K0 * t + OMEGA_LAMBDA0 * f64::powi(t, 3)) / t).sqrt()
 }
 
+/// Comoving distance to scale factor `a` in Mpc/h, negative in the future, by Simpson's
+/// rule in u = sqrt(a) where the integrand c da / (a^2 H) is smooth.
+pub fn comoving_distance(a: f64) -> f64 {
+    let n = 512;
+    let h = (1. - a.sqrt()) / n as f64;
+    let f = |u: f64| 2. / (OMEGA_M0 + OMEGA_K0 * u.powi(2) + OMEGA_LAMBDA0 * u.powi(6)).sqrt();
+    let sum: f64 = (0..=n)
+        .map(|i| {
+            let weight = match i {
+                0 => 1.,
+                i if i == n => 1.,
+                i => (2 + 2 * (i % 2)) as f64,
+            };
+            weight * f(a.sqrt() + i as f64 * h)
+        })
+        .sum();
+    HUBBLE_DISTANCE * sum * h / 3.
+}
+
+#[allow(non_snake_case)]
 pub fn D_t(t: f64) -> f64 {
     5. / 2.
         / OMEGA_M0
@@ -15,3 +63,68 @@ pub fn D_t(t: f64) -> f64 {
 pub fn hubble_constant(a: f64) -> f64 {
     H0 * (OMEGA_LAMBDA0 * a.powi(2) + OMEGA_K0 + OMEGA_M0 / a).sqrt()
 }
+
+fn e_squared(a: f64) -> f64 {
+    OMEGA_M0 / a.powi(3) + OMEGA_K0 / a.powi(2) + OMEGA_LAMBDA0
+}
+
+pub fn omega_m(a: f64) -> f64 {
+    OMEGA_M0 / a.powi(3) / e_squared(a)
+}
+
+// Carroll, Press & Turner (1992) growth suppression g(a) = D(a) / a
+fn growth_suppression(a: f64) -> f64 {
+    let omega_m = omega_m(a);
+    let omega_lambda = OMEGA_LAMBDA0 / e_squared(a);
+    5. / 2. * omega_m
+        / (omega_m.powf(4. / 7.) - omega_lambda + (1. + omega_m / 2.) * (1. + omega_lambda / 70.))
+}
+
+/// Linear growth factor normalized to D(a) = a during matter domination.
+pub fn matter_era_growth_factor(a: f64) -> f64 {
+    a * growth_suppression(a)
+}
+
+/// Linear growth factor normalized to D(1) = 1.
+pub fn growth_factor(a: f64) -> f64 {
+    a * growth_suppression(a) / growth_suppression(1.)
+}
+
+/// Linear growth rate f = dln D / dln a.
+pub fn growth_rate(a: f64) -> f64 {
+    omega_m(a).powf(5. / 9.)
+}
+
+/// Second-order growth factor relative to the first, D2 / D1^2 (Bouchet et al. 1995).
+pub fn second_order_growth_ratio(a: f64) -> f64 {
+    -3. / 7. * omega_m(a).powf(-1. / 143.)
+}
+
+/// Second-order growth rate f2 = dln D2 / dln a.
+pub fn second_order_growth_rate(a: f64) -> f64 {
+    2. * omega_m(a).powf(6. / 11.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comoving_distance_integrates_hubble_rate() {
+        assert_eq!(comoving_distance(1.), 0.);
+        // Hubble law at low redshift
+        let z = 1e-3;
+        assert!((comoving_distance(1. / (1. + z)) / (HUBBLE_DISTANCE * z) - 1.).abs() < 1e-3);
+        // d chi / da = -c / (a^2 H)
+        let (a, da) = (0.4, 1e-5);
+        let slope = (comoving_distance(a + da) - comoving_distance(a - da)) / (2. * da);
+        let expected = -HUBBLE_DISTANCE * H0 / (a * hubble_constant(a));
+        assert!(
+            (slope / expected - 1.).abs() < 1e-6,
+            "{} vs {}",
+            slope,
+            expected
+        );
+        assert!(comoving_distance(2.) < 0.);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::config::N_CELLS;
+
+/// Space-filling curve used to order particles in memory by the cell they occupy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceFillingCurve {
+    /// Z-order, bit interleaving of the cell indices
+    Morton,
+    /// Hilbert curve, consecutive keys are always neighbouring cells
+    Hilbert,
+}
+
+impl SpaceFillingCurve {
+    /// Key of the cell containing `x`, in cell units.
+    pub fn key(&self, x: [f64; 3]) -> u64 {
+        let cell = x.map(|x| (x.floor() as i64).rem_euclid(N_CELLS as i64) as u32);
+        match self {
+            SpaceFillingCurve::Morton => morton_key(cell, bits()),
+            SpaceFillingCurve::Hilbert => hilbert_key(cell, bits()),
+        }
+    }
+}
+
+// bits per axis covering N_CELLS
+fn bits() -> u32 {
+    N_CELLS.next_power_of_two().trailing_zeros().max(1)
+}
+
+fn interleave(x: [u32; 3], bits: u32) -> u64 {
+    (0..bits).rev().fold(0, |key, j| {
+        x.iter()
+            .fold(key, |key, x| (key << 1) | ((x >> j) & 1) as u64)
+    })
+}
+
+pub fn morton_key(cell: [u32; 3], bits: u32) -> u64 {
+    interleave(cell, bits)
+}
+
+/// Hilbert index via the transpose form of Skilling (2004), AIP Conf. Proc. 707, 381.
+pub fn hilbert_key(mut x: [u32; 3], bits: u32) -> u64 {
+    let m = 1 << (bits - 1);
+
+    // inverse undo
+    let mut q = m;
+    while q > 1 {
+        let p = q - 1;
+        for i in 0..3 {
+            if x[i] & q != 0 {
+                x[0] ^= p;
+            } else {
+                let t = (x[0] ^ x[i]) & p;
+                x[0] ^= t;
+                x[i] ^= t;
+            }
+        }
+        q >>= 1;
+    }
+
+    // Gray encode
+    for i in 1..3 {
+        x[i] ^= x[i - 1];
+    }
+    let mut t = 0;
+    q = m;
+    while q > 1 {
+        if x[2] & q != 0 {
+            t ^= q - 1;
+        }
+        q >>= 1;
+    }
+    x.iter_mut().for_each(|x| *x ^= t);
+
+    interleave(x, bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(bits: u32) -> Vec<[u32; 3]> {
+        let n = 1 << bits;
+        (0..n)
+            .flat_map(|i| (0..n).flat_map(move |j| (0..n).map(move |k| [i, j, k])))
+            .collect()
+    }
+
+    #[test]
+    fn morton_interleaves_bits() {
+        assert_eq!(morton_key([1, 0, 0], 2), 0b100);
+        assert_eq!(morton_key([0, 0, 1], 2), 0b001);
+        assert_eq!(morton_key([3, 3, 3], 2), 0b111111);
+    }
+
+    #[test]
+    fn hilbert_steps_between_neighbours() {
+        let bits = 4;
+        let mut by_key: Vec<(u64, [u32; 3])> = cells(bits)
+            .into_iter()
+            .map(|c| (hilbert_key(c, bits), c))
+            .collect();
+        by_key.sort();
+        // a bijection onto 0..n^3 ...
+        by_key
+            .iter()
+            .enumerate()
+            .for_each(|(i, (key, _))| assert_eq!(*key, i as u64));
+        // ... where every step moves to an adjacent cell
+        by_key.windows(2).for_each(|w| {
+            let step: u32 = (0..3).map(|i| w[0].1[i].abs_diff(w[1].1[i])).sum();
+            assert_eq!(step, 1, "{:?} -> {:?}", w[0].1, w[1].1);
+        });
+    }
+}
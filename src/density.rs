@@ -1,57 +1,113 @@
-use ndarray::{s, Array2, Array3, Axis};
-use rand::Rng;
+use ndarray::{Array1, Array2, Array3, ArrayViewMut2, Axis};
+use rayon::prelude::*;
 
-use crate::config::N_CELLS;
+use crate::{config::N_CELLS, float::Float};
+
+pub fn density<F: Float>(positions: &Array2<F>, mass: F) -> Array3<F> {
+    weighted_density(
+        positions,
+        &Array1::from_elem(positions.len_of(Axis(1)), mass),
+    )
+}
+
+/// CIC deposit with a mass per particle, e.g. for mixed-resolution zoom runs. Each z
+/// plane of the grid is filled by one rayon task from the particles in the cells on
+/// either side of it, always in column order, so the result is bit-identical for any
+/// number of threads. Sums are taken in `f64` whatever the storage precision, so
+/// single-precision runs don't lose the contributions of many light particles.
+pub fn weighted_density<F: Float>(positions: &Array2<F>, masses: &Array1<F>) -> Array3<F> {
+    let mut below: Vec<Vec<usize>> = vec![vec![]; N_CELLS];
+    positions
+        .row(2)
+        .iter()
+        .enumerate()
+        .for_each(|(i, z)| below[cell(z.f64())].push(i));
 
-pub fn density(positions: &Array2<f64>, mass: f64) -> Array3<f64> {
     let mut grid = Array3::<f64>::zeros((N_CELLS, N_CELLS, N_CELLS));
-    let len = positions.len_of(Axis(1));
-
-    for i in 0..len {
-        let x = positions.get((0, i)).unwrap();
-        let y = positions.get((1, i)).unwrap();
-        let z = positions.get((2, i)).unwrap();
-
-        let x_c = (x.floor() as usize).rem_euclid(N_CELLS);
-        let y_c = (y.floor() as usize).rem_euclid(N_CELLS);
-        let z_c = (z.floor() as usize).rem_euclid(N_CELLS);
-
-        let d_x = x - (x_c as f64);
-        let d_y = y - (y_c as f64);
-        let d_z = z - (z_c as f64);
-
-        let [t_x, t_y, t_z] = [d_x, d_y, d_z].map(|x| 1. - x);
-
-        let [X, Y, Z] = [x_c, y_c, z_c].map(|x| (x + 1).rem_euclid(N_CELLS));
-
-        grid.slice_mut(s![z_c, y_c, x_c])
-            .iter_mut()
-            .for_each(|x| *x += mass * t_x * t_y * t_z);
-
-        grid.slice_mut(s![z_c, y_c, X])
-            .iter_mut()
-            .for_each(|x| *x += mass * d_x * t_y * t_z);
-        grid.slice_mut(s![z_c, Y, x_c])
-            .iter_mut()
-            .for_each(|x| *x += mass * t_x * d_y * t_z);
-        grid.slice_mut(s![Z, y_c, x_c])
-            .iter_mut()
-            .for_each(|x| *x += mass * t_x * t_y * d_z);
-
-        grid.slice_mut(s![z_c, Y, X])
-            .iter_mut()
-            .for_each(|x| *x += mass * d_x * d_y * t_z);
-        grid.slice_mut(s![Z, Y, x_c])
-            .iter_mut()
-            .for_each(|x| *x += mass * t_x * d_y * d_z);
-        grid.slice_mut(s![Z, y_c, X])
-            .iter_mut()
-            .for_each(|x| *x += mass * d_x * t_y * d_z);
-
-        grid.slice_mut(s![Z, Y, X])
-            .iter_mut()
-            .for_each(|x| *x += mass * d_x * d_y * d_z);
-    }
+    grid.axis_iter_mut(Axis(0))
+        .into_par_iter()
+        .enumerate()
+        .for_each(|(z, mut plane)| {
+            let z_c = (z + N_CELLS - 1) % N_CELLS;
+            for (particles, lower) in [(&below[z], true), (&below[z_c], false)] {
+                for i in particles {
+                    let [x, y, z] = [0, 1, 2].map(|axis| positions[[axis, *i]].f64());
+                    let d_z = z - z.floor();
+                    let w_z = if lower { 1. - d_z } else { d_z };
+                    deposit(&mut plane, [x, y], masses[*i].f64() * w_z);
+                }
+            }
+        });
+    grid.mapv(F::of)
+}
+
+fn cell(x: f64) -> usize {
+    (x.floor() as usize).rem_euclid(N_CELLS)
+}
+
+// CIC deposit onto one z plane of the grid
+fn deposit(plane: &mut ArrayViewMut2<f64>, [x, y]: [f64; 2], mass: f64) {
+    let x_c = cell(x);
+    let y_c = cell(y);
+
+    let d_x = x - x.floor();
+    let d_y = y - y.floor();
 
-    grid
+    let [t_x, t_y] = [d_x, d_y].map(|x| 1. - x);
+
+    let [x_n, y_n] = [x_c, y_c].map(|x| (x + 1).rem_euclid(N_CELLS));
+
+    plane[[y_c, x_c]] += mass * t_x * t_y;
+    plane[[y_c, x_n]] += mass * d_x * t_y;
+    plane[[y_n, x_c]] += mass * t_x * d_y;
+    plane[[y_n, x_n]] += mass * d_x * d_y;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+
+    #[test]
+    fn parallel_deposit_matches_serial() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let n = 20000;
+        let positions = Array2::from_shape_simple_fn((3, n), || rng.gen_range(0.0..N_CELLS as f64));
+        let masses = Array1::from_shape_simple_fn(n, || rng.gen_range(0.5..2.));
+
+        let mut serial = Array3::zeros((N_CELLS, N_CELLS, N_CELLS));
+        positions
+            .columns()
+            .into_iter()
+            .zip(&masses)
+            .for_each(|(x, m)| {
+                let (z_c, d_z) = (cell(x[2]), x[2] - x[2].floor());
+                for (z, w_z) in [(z_c, 1. - d_z), ((z_c + 1) % N_CELLS, d_z)] {
+                    let mut plane = serial.index_axis_mut(Axis(0), z);
+                    deposit(&mut plane, [x[0], x[1]], m * w_z);
+                }
+            });
+
+        let run = |threads| {
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| weighted_density(&positions, &masses))
+        };
+        let parallel = run(4);
+        assert_eq!(parallel, run(1));
+        assert!((serial.sum() - masses.sum()).abs() < 1e-8);
+        serial
+            .iter()
+            .zip(parallel.iter())
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-10 * a.abs().max(1.)));
+
+        let single = weighted_density(&positions.mapv(|x| x as f32), &masses.mapv(|m| m as f32));
+        serial
+            .iter()
+            .zip(single.iter())
+            .for_each(|(a, b)| assert!((a - *b as f64).abs() < 1e-5 * a.abs().max(1.)));
+    }
 }
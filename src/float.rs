@@ -0,0 +1,36 @@
+use ndarray::NdFloat;
+use rustfft::{num_traits::FloatConst, FftNum};
+
+/// Float type the main loop stores particles and meshes in, see `Float`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    Single,
+    Double,
+}
+
+/// Storage precision of particle and mesh arrays. Runs that are memory bound can use
+/// `f32`; sums over particles and CIC weights are still taken in `f64`.
+pub trait Float: NdFloat + FftNum + FloatConst {
+    fn of(x: f64) -> Self;
+    fn f64(self) -> f64;
+}
+
+impl Float for f32 {
+    fn of(x: f64) -> Self {
+        x as f32
+    }
+
+    fn f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Float for f64 {
+    fn of(x: f64) -> Self {
+        x
+    }
+
+    fn f64(self) -> f64 {
+        self
+    }
+}
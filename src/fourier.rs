@@ -1,45 +1,46 @@
-use std::f64::{consts::PI, INFINITY};
+use std::f64::consts::PI;
 
 use crate::{
     config::{DIV_BY_ZERO, N_CELLS},
+    float::Float,
     meshgrid::Meshgrid3,
 };
-use ndarray::{s, Array, Array1, Array3};
+use ndarray::Array3;
 use ndrustfft::{ndfft, ndifft, FftHandler};
-use rustfft::{num_complex::Complex, FftPlanner};
+use rustfft::num_complex::Complex;
 
-pub fn forward(a: &Array3<Complex<f64>>) -> Array3<Complex<f64>> {
+pub fn forward<F: Float>(a: &Array3<Complex<F>>) -> Array3<Complex<F>> {
     let (nx, ny, nz) = a.dim();
 
-    let mut vhat: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
+    let mut vhat: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
 
-    let mut handler_ax0 = FftHandler::<f64>::new(nx);
-    let mut handler_ax1 = FftHandler::<f64>::new(ny);
-    let mut handler_ax2 = FftHandler::<f64>::new(nz);
+    let mut handler_ax0 = FftHandler::<F>::new(nx);
+    let mut handler_ax1 = FftHandler::<F>::new(ny);
+    let mut handler_ax2 = FftHandler::<F>::new(nz);
 
-    let mut work2: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
-    let mut work1: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
+    let mut work2: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
+    let mut work1: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
 
-    ndfft(&a, &mut work2, &mut handler_ax2, 2);
+    ndfft(a, &mut work2, &mut handler_ax2, 2);
     ndfft(&work2, &mut work1, &mut handler_ax1, 1);
     ndfft(&work1, &mut vhat, &mut handler_ax0, 0);
 
     vhat
 }
 
-pub fn inverse(a: &Array3<Complex<f64>>) -> Array3<Complex<f64>> {
+pub fn inverse<F: Float>(a: &Array3<Complex<F>>) -> Array3<Complex<F>> {
     let (nx, ny, nz) = a.dim();
 
-    let mut vhat: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
+    let mut vhat: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
 
-    let mut handler_ax0 = FftHandler::<f64>::new(nx);
-    let mut handler_ax1 = FftHandler::<f64>::new(ny);
-    let mut handler_ax2 = FftHandler::<f64>::new(nz);
+    let mut handler_ax0 = FftHandler::<F>::new(nx);
+    let mut handler_ax1 = FftHandler::<F>::new(ny);
+    let mut handler_ax2 = FftHandler::<F>::new(nz);
 
-    let mut work2: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
-    let mut work1: Array3<Complex<f64>> = Array3::zeros((nx, ny, nz));
+    let mut work2: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
+    let mut work1: Array3<Complex<F>> = Array3::zeros((nx, ny, nz));
 
-    ndifft(&a, &mut work1, &mut handler_ax0, 0);
+    ndifft(a, &mut work1, &mut handler_ax0, 0);
     ndifft(&work1, &mut work2, &mut handler_ax1, 1);
     ndifft(&work2, &mut vhat, &mut handler_ax2, 2);
 
@@ -57,7 +58,7 @@ pub fn sample_freq(n: &usize) -> Vec<f64> {
         .collect::<Vec<f64>>();
 
     res[len..].reverse();
-    res[len..].iter_mut().for_each(|x| *x = -1. * (*x + 1.));
+    res[len..].iter_mut().for_each(|x| *x = -(*x + 1.));
     res.iter().map(|x| *x / (*n as f64)).collect::<Vec<f64>>()
 }
 
@@ -77,17 +78,10 @@ pub fn ksq_inv() -> Array3<f64> {
 }
 #[cfg(test)]
 mod tests {
-    use std::f64::consts::PI;
-
-    use ndarray::{Array, Array3, Zip};
+    use ndarray::{Array3, Zip};
     use ndrustfft::Complex;
-    use rustfft::num_complex::ComplexFloat;
 
-    use crate::{
-        config::{BOX_SIZE, N_PARTICLES},
-        fourier::{inverse, ksq_inv},
-        meshgrid::Meshgrid3,
-    };
+    use crate::fourier::{inverse, ksq_inv};
 
     use super::{forward, sample_freq};
 
@@ -96,7 +90,7 @@ mod tests {
         {
             let n: usize = 10;
             let ans = sample_freq(&n);
-            let expected_result = vec![0.0, 0.1, 0.2, 0.3, 0.4, -1.4, -1.3, -1.2, -1.1, -1.0];
+            let expected_result = vec![0.0, 0.1, 0.2, 0.3, 0.4, -0.5, -0.4, -0.3, -0.2, -0.1];
             ans.iter()
                 .zip(expected_result)
                 .for_each(|(a, b)| assert_eq!(*a, b));
@@ -111,11 +105,11 @@ mod tests {
                 0.2727272727272727,
                 0.36363636363636365,
                 0.45454545454545453,
-                -1.3636363636363638,
-                -1.2727272727272727,
-                -1.1818181818181819,
-                -1.0909090909090908,
-                -1.0,
+                -0.45454545454545453,
+                -0.36363636363636365,
+                -0.2727272727272727,
+                -0.18181818181818182,
+                -0.09090909090909091,
             ];
             ans.iter()
                 .zip(expected_result)
@@ -0,0 +1,406 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use ndarray::{Array1, Array2};
+
+use crate::{
+    config::{BOX_SIZE, H0, N_CELLS, OMEGA_LAMBDA0, OMEGA_M0},
+    cosmology::RHO_CRIT,
+    particles::{ParticleType, Particles},
+};
+
+const HEADER_SIZE: usize = 256;
+
+/// Record layout of a GADGET-2 binary snapshot. Both wrap every block in Fortran
+/// record markers; format 2 precedes each block with a small record holding its
+/// four-character name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GadgetFormat {
+    One,
+    Two,
+}
+
+/// GADGET-2 snapshot header, in GADGET units: kpc/h, 10^10 M_sun/h and km/s.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GadgetHeader {
+    /// Particles of each GADGET type in this file
+    pub npart: [u32; 6],
+    /// Mass of each type, zero when it varies and is stored per particle
+    pub mass: [f64; 6],
+    /// Scale factor
+    pub time: f64,
+    pub redshift: f64,
+    pub npart_total: [u64; 6],
+    pub box_size: f64,
+    pub omega0: f64,
+    pub omega_lambda: f64,
+    pub hubble_param: f64,
+}
+
+impl GadgetHeader {
+    // conversions from grid units; a mean-density cell has unit mass
+    fn kpc_per_cell(&self) -> f64 {
+        self.box_size / N_CELLS as f64
+    }
+
+    fn mass_unit(&self) -> f64 {
+        self.omega0 * RHO_CRIT * (self.kpc_per_cell() / 1e3).powi(3) / 1e10
+    }
+
+    // GADGET stores the peculiar velocity a dx/dt over sqrt(a), and p = a^2 dx/dt with
+    // time in 1/H0, so 100 km/s per Mpc/h
+    fn velocity_unit(&self) -> f64 {
+        100. * self.kpc_per_cell() / 1e3 / self.time.powf(1.5)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE);
+        self.npart
+            .iter()
+            .for_each(|n| put(&mut out, n.to_le_bytes()));
+        self.mass
+            .iter()
+            .for_each(|m| put(&mut out, m.to_le_bytes()));
+        put(&mut out, self.time.to_le_bytes());
+        put(&mut out, self.redshift.to_le_bytes());
+        put(&mut out, [0; 8]); // flag_sfr, flag_feedback
+        let low = self.npart_total.map(|n| n as u32);
+        low.iter().for_each(|n| put(&mut out, n.to_le_bytes()));
+        put(&mut out, 0i32.to_le_bytes()); // flag_cooling
+        put(&mut out, 1i32.to_le_bytes()); // num_files
+        for x in [
+            self.box_size,
+            self.omega0,
+            self.omega_lambda,
+            self.hubble_param,
+        ] {
+            put(&mut out, x.to_le_bytes());
+        }
+        put(&mut out, [0; 8]); // flag_stellarage, flag_metals
+        let high = self.npart_total.map(|n| (n >> 32) as u32);
+        high.iter().for_each(|n| put(&mut out, n.to_le_bytes()));
+        out.resize(HEADER_SIZE, 0);
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<GadgetHeader> {
+        if bytes.len() != HEADER_SIZE {
+            return Err(invalid("header block is not 256 bytes"));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let f64_at = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let npart = [0, 1, 2, 3, 4, 5].map(|t| u32_at(4 * t));
+        Ok(GadgetHeader {
+            npart,
+            mass: [0, 1, 2, 3, 4, 5].map(|t| f64_at(24 + 8 * t)),
+            time: f64_at(72),
+            redshift: f64_at(80),
+            npart_total: [0, 1, 2, 3, 4, 5]
+                .map(|t| u32_at(96 + 4 * t) as u64 | (u32_at(168 + 4 * t) as u64) << 32),
+            box_size: f64_at(128),
+            omega0: f64_at(136),
+            omega_lambda: f64_at(144),
+            hubble_param: f64_at(152),
+        })
+    }
+}
+
+/// GADGET type of each particle type: gas, halo, disk and "bndry" for the coarse
+/// particles around a zoom region.
+fn gadget_type(kind: ParticleType) -> usize {
+    match kind {
+        ParticleType::Baryon => 0,
+        ParticleType::DarkMatter => 1,
+        ParticleType::Neutrino => 2,
+        ParticleType::Boundary => 5,
+    }
+}
+
+fn particle_type(gadget_type: usize) -> ParticleType {
+    match gadget_type {
+        0 | 4 => ParticleType::Baryon,
+        1 => ParticleType::DarkMatter,
+        2 => ParticleType::Neutrino,
+        _ => ParticleType::Boundary,
+    }
+}
+
+/// Writes a single-file GADGET-2 snapshot of `particles` at scale factor `a`, sorted
+/// by GADGET type as the format requires. Baryons are written as gas at zero internal
+/// energy; IDs are 32 bits unless they don't fit.
+pub fn write_gadget(
+    path: impl AsRef<Path>,
+    particles: &Particles,
+    a: f64,
+    format: GadgetFormat,
+) -> Result<()> {
+    let mut order: Vec<usize> = (0..particles.len()).collect();
+    order.sort_by_key(|p| gadget_type(particles.types[*p]));
+    let kind = |p: usize| gadget_type(particles.types[p]);
+
+    let mut header = GadgetHeader {
+        time: a,
+        redshift: 1. / a - 1.,
+        box_size: 1e3 * BOX_SIZE as f64,
+        omega0: OMEGA_M0,
+        omega_lambda: OMEGA_LAMBDA0,
+        hubble_param: H0,
+        ..Default::default()
+    };
+    let mass_unit = header.mass_unit();
+    order.iter().for_each(|p| header.npart[kind(*p)] += 1);
+    header.npart_total = header.npart.map(|n| n as u64);
+    for t in 0..6 {
+        let mut masses = order
+            .iter()
+            .filter(|p| kind(**p) == t)
+            .map(|p| particles.masses[*p]);
+        if let Some(first) = masses.next() {
+            if masses.all(|m| m == first) {
+                header.mass[t] = first * mass_unit;
+            }
+        }
+    }
+
+    let mut out = vec![];
+    write_block(&mut out, format, b"HEAD", &header.to_bytes());
+    let vectors = |x: &Array2<f64>, scale: f64| {
+        let mut data = Vec::with_capacity(12 * order.len());
+        for p in &order {
+            (0..3).for_each(|i| put(&mut data, ((x[[i, *p]] * scale) as f32).to_le_bytes()));
+        }
+        data
+    };
+    let positions = vectors(&particles.positions, header.kpc_per_cell());
+    let velocities = vectors(&particles.velocities, header.velocity_unit());
+    write_block(&mut out, format, b"POS ", &positions);
+    write_block(&mut out, format, b"VEL ", &velocities);
+
+    let mut ids = vec![];
+    if particles.ids.iter().all(|id| *id <= u32::MAX as u64) {
+        order
+            .iter()
+            .for_each(|p| put(&mut ids, (particles.ids[*p] as u32).to_le_bytes()));
+    } else {
+        order
+            .iter()
+            .for_each(|p| put(&mut ids, particles.ids[*p].to_le_bytes()));
+    }
+    write_block(&mut out, format, b"ID  ", &ids);
+
+    let mut masses = vec![];
+    for p in order.iter().filter(|p| header.mass[kind(**p)] == 0.) {
+        put(
+            &mut masses,
+            ((particles.masses[*p] * mass_unit) as f32).to_le_bytes(),
+        );
+    }
+    if !masses.is_empty() {
+        write_block(&mut out, format, b"MASS", &masses);
+    }
+    if header.npart[0] > 0 {
+        write_block(
+            &mut out,
+            format,
+            b"U   ",
+            &vec![0; 4 * header.npart[0] as usize],
+        );
+    }
+    fs::write(path, out)
+}
+
+/// Reads a single-file GADGET-2 snapshot in either format, converting back to grid
+/// units with the header's box size and Ω_m.
+pub fn read_gadget(path: impl AsRef<Path>) -> Result<(GadgetHeader, Particles)> {
+    let bytes = fs::read(path)?;
+    let format = match bytes
+        .get(..4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    {
+        Some(8) => GadgetFormat::Two,
+        Some(256) => GadgetFormat::One,
+        _ => return Err(invalid("not a little-endian GADGET-2 snapshot")),
+    };
+    let mut blocks = Blocks {
+        bytes: &bytes,
+        at: 0,
+        format,
+    };
+
+    let header = GadgetHeader::from_bytes(blocks.next(b"HEAD")?)?;
+    let types: Vec<usize> = (0..6)
+        .flat_map(|t| vec![t; header.npart[t] as usize])
+        .collect();
+    let n = types.len();
+    let vectors = |data: &[u8], scale: f64| -> Result<Array2<f64>> {
+        let x = floats(data, 3 * n)?;
+        let x = Array2::from_shape_vec((n, 3), x).unwrap().reversed_axes();
+        Ok(x.map(|x| x / scale).as_standard_layout().to_owned())
+    };
+    let positions = vectors(blocks.next(b"POS ")?, header.kpc_per_cell())?
+        .mapv(|x| x.rem_euclid(N_CELLS as f64));
+    let velocities = vectors(blocks.next(b"VEL ")?, header.velocity_unit())?;
+
+    let data = blocks.next(b"ID  ")?;
+    let ids: Vec<u64> = if data.len() == 4 * n {
+        data.chunks(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64)
+            .collect()
+    } else if data.len() == 8 * n {
+        data.chunks(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect()
+    } else {
+        return Err(invalid("ID block does not match the particle count"));
+    };
+
+    let variable = types.iter().filter(|t| header.mass[**t] == 0.).count();
+    let mut stored = if variable > 0 {
+        floats(blocks.next(b"MASS")?, variable)?.into_iter()
+    } else {
+        vec![].into_iter()
+    };
+    let masses = types
+        .iter()
+        .map(|t| match header.mass[*t] {
+            0. => stored.next().unwrap(),
+            m => m,
+        } / header.mass_unit())
+        .collect::<Vec<f64>>();
+
+    let particles = Particles {
+        ids: Array1::from(ids),
+        positions,
+        velocities,
+        masses: Array1::from(masses),
+        types: types.into_iter().map(particle_type).collect(),
+    };
+    Ok((header, particles))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn put<const N: usize>(out: &mut Vec<u8>, bytes: [u8; N]) {
+    out.extend_from_slice(&bytes);
+}
+
+fn write_block(out: &mut Vec<u8>, format: GadgetFormat, label: &[u8; 4], data: &[u8]) {
+    if format == GadgetFormat::Two {
+        put(out, 8u32.to_le_bytes());
+        out.extend_from_slice(label);
+        put(out, (data.len() as u32 + 8).to_le_bytes());
+        put(out, 8u32.to_le_bytes());
+    }
+    put(out, (data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    put(out, (data.len() as u32).to_le_bytes());
+}
+
+fn floats(data: &[u8], len: usize) -> Result<Vec<f64>> {
+    if data.len() != 4 * len {
+        return Err(invalid("block does not match the particle count"));
+    }
+    Ok(data
+        .chunks(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as f64)
+        .collect())
+}
+
+// Fortran records of a snapshot, in file order
+struct Blocks<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    format: GadgetFormat,
+}
+
+impl<'a> Blocks<'a> {
+    fn record(&mut self) -> Result<&'a [u8]> {
+        let marker = |at: usize| -> Result<usize> {
+            self.bytes
+                .get(at..at + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .ok_or_else(|| invalid("snapshot ends mid-record"))
+        };
+        let len = marker(self.at)?;
+        let start = self.at + 4;
+        if marker(start + len)? != len {
+            return Err(invalid("mismatched record markers"));
+        }
+        self.at = start + len + 4;
+        Ok(&self.bytes[start..start + len])
+    }
+
+    fn next(&mut self, label: &[u8; 4]) -> Result<&'a [u8]> {
+        if self.format == GadgetFormat::Two && &self.record()?[..4] != label {
+            return Err(invalid(&format!(
+                "expected block {}",
+                String::from_utf8_lossy(label)
+            )));
+        }
+        self.record()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut particles = Particles::new(
+            array![[0.5, 15.9], [1.5, 2.], [3., 4.]],
+            array![[0.1, -0.2], [0., 0.3], [1., 2.]],
+            1.,
+            ParticleType::DarkMatter,
+        );
+        let mut boundary = Particles::new(
+            array![[8., 9.], [8., 9.], [8., 9.]],
+            array![[0., 0.], [0., 0.], [0., 0.]],
+            8.,
+            ParticleType::Boundary,
+        );
+        boundary.masses[1] = 64.;
+        particles.append(boundary);
+        particles.append(Particles::new(
+            array![[4.], [4.], [4.]],
+            array![[-1.], [0.], [0.]],
+            0.5,
+            ParticleType::Baryon,
+        ));
+        let a = 0.25;
+
+        for (format, marker) in [(GadgetFormat::One, 256), (GadgetFormat::Two, 8)] {
+            let path = std::env::temp_dir().join(format!("nbody_test_snapshot_{:?}", format));
+            write_gadget(&path, &particles, a, format).unwrap();
+            assert_eq!(fs::read(&path).unwrap()[..4], (marker as u32).to_le_bytes());
+            let (header, read) = read_gadget(&path).unwrap();
+            let _ = fs::remove_file(path);
+
+            assert_eq!(header.npart, [1, 2, 0, 0, 0, 2]);
+            assert!((header.redshift - 3.).abs() < 1e-12);
+            assert_eq!(header.box_size, 1e3 * BOX_SIZE as f64);
+            assert_eq!((header.omega0, header.hubble_param), (OMEGA_M0, H0));
+            assert!((header.mass[1] - header.mass_unit()).abs() < 1e-12);
+            assert_eq!(header.mass[5], 0.);
+
+            // gas first, then halo and boundary particles
+            assert_eq!(read.ids, array![4, 0, 1, 2, 3]);
+            for (p, id) in read.ids.iter().enumerate() {
+                let q = particles.index_of(*id).unwrap();
+                assert_eq!(read.types[p], particles.types[q]);
+                assert!((read.masses[p] / particles.masses[q] - 1.).abs() < 1e-6);
+                for i in 0..3 {
+                    let close = |a: f64, b: f64| (a - b).abs() < 1e-5 * b.abs().max(1.);
+                    assert!(close(read.positions[[i, p]], particles.positions[[i, q]]));
+                    assert!(close(read.velocities[[i, p]], particles.velocities[[i, q]]));
+                }
+            }
+        }
+    }
+}
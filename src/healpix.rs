@@ -0,0 +1,213 @@
+//! HEALPix pixelization of the sphere (Górski et al. 2005, ApJ 622, 759) in the ring
+//! and nested schemes, following the reference `healpix_base` algorithms.
+
+use std::f64::consts::{FRAC_PI_2, PI};
+
+/// Order of the pixels in a map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ordering {
+    /// Iso-latitude rings from north to south, increasing in longitude along each
+    Ring,
+    /// Hierarchical, each pixel subdivided into four consecutive ones at twice the
+    /// resolution
+    Nested,
+}
+
+pub fn npix(nside: usize) -> usize {
+    12 * nside * nside
+}
+
+/// An iso-latitude ring, counted from 1 at the north pole to 4 nside - 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ring {
+    /// Ring-scheme index of its first pixel
+    pub start: usize,
+    pub len: usize,
+    /// cos(theta) of the pixel centres
+    pub z: f64,
+    /// Longitude of the first pixel centre
+    pub phi_0: f64,
+}
+
+pub fn ring(nside: usize, i: usize) -> Ring {
+    let n = nside as f64;
+    let north = i.min(4 * nside - i);
+    let (start, len, z, phi_0) = if north < nside {
+        // polar caps
+        let z = 1. - (north * north) as f64 / (3. * n * n);
+        let start = 2 * north * (north - 1);
+        (start, 4 * north, z, PI / (4 * north) as f64)
+    } else {
+        let z = (2. * n - north as f64) * 2. / (3. * n);
+        let start = 2 * nside * (nside - 1) + (north - nside) * 4 * nside;
+        let shifted = (i - nside).is_multiple_of(2);
+        let phi_0 = if shifted { PI / (4. * n) } else { 0. };
+        (start, 4 * nside, z, phi_0)
+    };
+    if i > 2 * nside {
+        Ring {
+            start: npix(nside) - start - len,
+            len,
+            z: -z,
+            phi_0,
+        }
+    } else {
+        Ring {
+            start,
+            len,
+            z,
+            phi_0,
+        }
+    }
+}
+
+/// Ring of a ring-scheme pixel, by bisection over the ring starts.
+fn ring_of(nside: usize, pixel: usize) -> Ring {
+    let (mut low, mut high) = (1, 4 * nside - 1);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if ring(nside, mid).start <= pixel {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    ring(nside, low)
+}
+
+/// (theta, phi) of the centre of a ring-scheme pixel.
+pub fn pix2ang_ring(nside: usize, pixel: usize) -> (f64, f64) {
+    let ring = ring_of(nside, pixel);
+    let phi = ring.phi_0 + 2. * PI * (pixel - ring.start) as f64 / ring.len as f64;
+    (ring.z.acos(), phi)
+}
+
+pub fn ang2pix(nside: usize, ordering: Ordering, theta: f64, phi: f64) -> usize {
+    match ordering {
+        Ordering::Ring => ang2pix_ring(nside, theta.cos(), phi),
+        Ordering::Nested => ang2pix_nest(nside, theta.cos(), phi),
+    }
+}
+
+/// Pixel containing the direction of `v`, which need not be normalized.
+pub fn vec2pix(nside: usize, ordering: Ordering, v: [f64; 3]) -> usize {
+    let r = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let phi = v[1].atan2(v[0]);
+    match ordering {
+        Ordering::Ring => ang2pix_ring(nside, v[2] / r, phi),
+        Ordering::Nested => ang2pix_nest(nside, v[2] / r, phi),
+    }
+}
+
+// longitude in units of pi / 2, in [0, 4)
+fn longitude(phi: f64) -> f64 {
+    (phi / FRAC_PI_2).rem_euclid(4.)
+}
+
+fn ang2pix_ring(nside: usize, z: f64, phi: f64) -> usize {
+    let n = nside as i64;
+    let tt = longitude(phi);
+    let za = z.abs();
+    if za <= 2. / 3. {
+        let t_1 = nside as f64 * (0.5 + tt);
+        let t_2 = nside as f64 * z * 0.75;
+        let jp = (t_1 - t_2) as i64;
+        let jm = (t_1 + t_2) as i64;
+        let ir = n + 1 + jp - jm;
+        let kshift = 1 - (ir & 1);
+        let ip = ((jp + jm - n + kshift + 1) / 2).rem_euclid(4 * n);
+        (2 * n * (n - 1) + (ir - 1) * 4 * n + ip) as usize
+    } else {
+        let tp = tt - tt.floor();
+        let tmp = nside as f64 * (3. * (1. - za)).sqrt();
+        let jp = (tp * tmp) as i64;
+        let jm = ((1. - tp) * tmp) as i64;
+        let ir = jp + jm + 1;
+        let ip = ((tt * ir as f64) as i64).rem_euclid(4 * ir);
+        if z > 0. {
+            (2 * ir * (ir - 1) + ip) as usize
+        } else {
+            (12 * n * n - 2 * ir * (ir + 1) + ip) as usize
+        }
+    }
+}
+
+fn ang2pix_nest(nside: usize, z: f64, phi: f64) -> usize {
+    assert!(
+        nside.is_power_of_two(),
+        "nested ordering needs a power of two nside"
+    );
+    let n = nside as i64;
+    let tt = longitude(phi);
+    let za = z.abs();
+    let (face, ix, iy) = if za <= 2. / 3. {
+        let t_1 = nside as f64 * (0.5 + tt);
+        let t_2 = nside as f64 * z * 0.75;
+        let jp = (t_1 - t_2) as i64;
+        let jm = (t_1 + t_2) as i64;
+        let (ifp, ifm) = (jp / n, jm / n);
+        let face = match ifp.cmp(&ifm) {
+            std::cmp::Ordering::Equal => ifp | 4,
+            std::cmp::Ordering::Less => ifp,
+            std::cmp::Ordering::Greater => ifm + 8,
+        };
+        (face, jm & (n - 1), n - (jp & (n - 1)) - 1)
+    } else {
+        let ntt = (tt as i64).min(3);
+        let tp = tt - ntt as f64;
+        let tmp = nside as f64 * (3. * (1. - za)).sqrt();
+        let jp = ((tp * tmp) as i64).min(n - 1);
+        let jm = (((1. - tp) * tmp) as i64).min(n - 1);
+        if z >= 0. {
+            (ntt, n - jm - 1, n - jp - 1)
+        } else {
+            (ntt + 8, jp, jm)
+        }
+    };
+    face as usize * nside * nside + spread(ix as u64) as usize + 2 * spread(iy as u64) as usize
+}
+
+// bits of x on the even positions
+fn spread(x: u64) -> u64 {
+    (0..32).fold(0, |out, b| out | ((x >> b) & 1) << (2 * b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_centres_round_trip() {
+        // at nside 1 both schemes number the twelve base faces alike
+        for p in 0..12 {
+            let (theta, phi) = pix2ang_ring(1, p);
+            assert_eq!(ang2pix(1, Ordering::Ring, theta, phi), p);
+            assert_eq!(ang2pix(1, Ordering::Nested, theta, phi), p);
+        }
+
+        for nside in [2, 4, 16] {
+            let mut nested = vec![false; npix(nside)];
+            let mut area = vec![0; npix(nside)];
+            for p in 0..npix(nside) {
+                let (theta, phi) = pix2ang_ring(nside, p);
+                assert_eq!(ang2pix(nside, Ordering::Ring, theta, phi), p);
+                nested[ang2pix(nside, Ordering::Nested, theta, phi)] = true;
+            }
+            assert!(nested.iter().all(|hit| *hit));
+
+            // equal areas: a uniform grid in (z, phi) fills the pixels evenly
+            let steps = 400;
+            for i in 0..steps {
+                for j in 0..steps {
+                    let z = -1. + 2. * (i as f64 + 0.5) / steps as f64;
+                    let phi = 2. * PI * (j as f64 + 0.5) / steps as f64;
+                    area[ang2pix(nside, Ordering::Ring, z.acos(), phi)] += 1;
+                }
+            }
+            let mean = (steps * steps) as f64 / npix(nside) as f64;
+            assert!(area.iter().all(|a| (*a as f64 / mean - 1.).abs() < 0.25));
+        }
+        assert_eq!(vec2pix(4, Ordering::Ring, [0., 0., 2.]), 0);
+        assert_eq!(vec2pix(4, Ordering::Ring, [0., 0., -1.]), npix(4) - 4);
+    }
+}
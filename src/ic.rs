@@ -1,126 +1,605 @@
-use ndarray::{s, Array, Array1, Array2, Array3};
-use rand::Rng;
-use rustfft::num_complex::{Complex, Complex64, ComplexFloat};
-use std::{
-    f64::{consts::PI, MIN_POSITIVE},
-    ops::Div,
-};
+use ndarray::{s, Array, Array1, Array2, Array3, Axis};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, UnitSphere};
+use rustfft::num_complex::{Complex, Complex64};
+use std::f64::consts::PI;
 
 use crate::{
-    config::{A_INIT, BOX_SIZE, DIV_BY_ZERO, N_CELLS, N_PARTICLES},
+    config::{A_INIT, BOX_SIZE, GLASS_STEPS, N_CELLS, N_PARTICLES, OMEGA_B0, OMEGA_M0, SEED},
     cosmology::*,
-    fourier::{self, inverse, sample_freq},
+    density::density,
+    fourier::{self, inverse, ksq_inv, sample_freq},
+    integrate::update,
     meshgrid::Meshgrid3,
+    particles::{ParticleType, Particles},
 };
 
-pub fn initial_conditions(density: Array3<f64>) -> (Array2<f64>, Array2<f64>) {
-    let mut positions: Array2<f64> = Array2::zeros((3, N_PARTICLES.pow(3)));
-    let mut velocities: Array2<f64> = Array2::zeros((3, N_PARTICLES.pow(3)));
+/// Order of Lagrangian perturbation theory used to displace the particles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LptOrder {
+    /// Zel'dovich approximation
+    First,
+    /// 2LPT, see Scoccimarro (1998)
+    Second,
+}
 
-    let density_k: Array3<Complex64> = density.clone().map(|x| Complex { re: *x, im: 0. });
-    let density_k: Array3<Complex64> = fourier::forward(&density_k);
+/// Pre-initial particle distribution the displacements are applied to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParticleLoad {
+    /// Regular cubic lattice
+    Lattice,
+    /// Poisson load relaxed under repulsive gravity for GLASS_STEPS steps
+    Glass,
+    /// Uniformly random positions
+    Poisson,
+}
 
-    for i in 0..=2 {
-        let potential: Array3<Complex64> = potential_k(&density_k);
-        let (_positions, _velocities): (Array1<f64>, Array1<f64>) = zeldovich(potential, i);
-        positions.slice_mut(s![i, ..]).assign(&_positions);
-        velocities.slice_mut(s![i, ..]).assign(&_velocities);
-    }
+/// Dark matter particles displaced from `load` by LPT of `density`, each carrying the
+/// mean density as mass and its pre-initial index as ID.
+pub fn initial_conditions(density: Array3<f64>, order: LptOrder, load: ParticleLoad) -> Particles {
+    let (positions, velocities) = displace(density, order, pre_initial_positions(load));
+    let mass = (N_CELLS as f64 / N_PARTICLES as f64).powi(3);
+    Particles::new(positions, velocities, mass, ParticleType::DarkMatter)
+}
 
-    (positions, velocities)
+/// CDM and baryon particles from density fields of each species sharing the same
+/// phases. Baryons sit on the lattice shifted by half a spacing, and the masses split
+/// the mean density by OMEGA_B0 / OMEGA_M0. Velocities follow each species' own
+/// displacement with the total matter growth rate. The second-order term is sourced
+/// by the total matter field, so both species share it.
+pub fn two_species_initial_conditions(
+    cdm: Array3<f64>,
+    baryon: Array3<f64>,
+    order: LptOrder,
+) -> Particles {
+    let mass = (N_CELLS as f64 / N_PARTICLES as f64).powi(3);
+    let f_b = OMEGA_B0 / OMEGA_M0;
+    let half_spacing = 0.5 * N_CELLS as f64 / N_PARTICLES as f64;
+    let potential_2 = match order {
+        LptOrder::First => None,
+        LptOrder::Second => {
+            let total = &cdm * (1. - f_b) + &baryon * f_b;
+            Some(second_order_potential(&density_k(total)))
+        }
+    };
+    let potential_2 = potential_2.as_ref();
+    let (positions, velocities) = displace_by(&density_k(cdm), potential_2, lattice());
+    let mut particles = Particles::new(
+        positions,
+        velocities,
+        (1. - f_b) * mass,
+        ParticleType::DarkMatter,
+    );
+    let (positions, velocities) = displace_by(
+        &density_k(baryon),
+        potential_2,
+        shifted_lattice(half_spacing),
+    );
+    particles.append(Particles::new(
+        positions,
+        velocities,
+        f_b * mass,
+        ParticleType::Baryon,
+    ));
+    particles
 }
 
-fn potential_k(density_k: &Array3<Complex64>) -> Array3<Complex64> {
-    let scale = 2. * PI * (N_PARTICLES as f64 / BOX_SIZE as f64);
-    let kx: Vec<f64> = sample_freq(&N_PARTICLES)
-        .iter()
-        .map(|x| x * scale)
+/// Particles of one neutrino species of `mass` eV on the lattice shifted by a quarter
+/// spacing, displaced by the Zel'dovich approximation of the total matter `density`
+/// damped below the free-streaming scale at A_INIT as
+/// delta_nu = delta_m (k_fs / (k + k_fs))^2, which follows the matter on large scales
+/// and falls as k^-2 on small ones. Each gets an isotropic Fermi-Dirac thermal
+/// velocity drawn from `seed` on top of the bulk flow, and its share of the mean
+/// density; the other species should give up the same fraction.
+pub fn neutrino_initial_conditions(density: Array3<f64>, mass: f64, seed: u64) -> Particles {
+    let quarter_spacing = 0.25 * N_CELLS as f64 / N_PARTICLES as f64;
+    let (positions, mut velocities) = displace_by(
+        &free_streaming(density_k(density), mass),
+        None,
+        shifted_lattice(quarter_spacing),
+    );
+
+    // the peculiar velocity q k_B T_nu c / (m a) decays as 1 / a, so p = a^2 dx/dt is
+    // constant; km/s -> Mpc/h per H0 -> cells
+    let scale =
+        neutrino_temperature() * SPEED_OF_LIGHT / mass / 100. * N_CELLS as f64 / BOX_SIZE as f64;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let momenta: Vec<f64> = fermi_dirac_momenta(velocities.len_of(Axis(1)), &mut rng);
+    velocities
+        .columns_mut()
+        .into_iter()
+        .zip(momenta)
+        .for_each(|(mut v, q)| {
+            let direction: [f64; 3] = UnitSphere.sample(&mut rng);
+            (0..3).for_each(|i| v[i] += scale * q * direction[i]);
+        });
+    let particle_mass = (N_CELLS as f64 / N_PARTICLES as f64).powi(3) * neutrino_fraction(mass);
+    Particles::new(positions, velocities, particle_mass, ParticleType::Neutrino)
+}
+
+fn free_streaming(mut density_k: Array3<Complex64>, mass: f64) -> Array3<Complex64> {
+    let k_fs = free_streaming_wavenumber(mass, A_INIT);
+    let [kx, ky, kz]: [Array3<f64>; 3] = wavevectors(density_k.dim().0);
+    let k: Array3<f64> = (&kx * &kx + &ky * &ky + &kz * &kz).mapv(f64::sqrt);
+    density_k.zip_mut_with(&k, |delta, k| *delta *= (k_fs / (k + k_fs)).powi(2));
+    density_k
+}
+
+// q = p c / (k_B T_nu) of the relic distribution q^2 / (e^q + 1), by inverting its
+// CDF tabulated up to q = 30.
+fn fermi_dirac_momenta(n: usize, rng: &mut impl Rng) -> Vec<f64> {
+    let dq = 0.01;
+    let cdf: Vec<f64> = (0..3000)
+        .scan(0., |total, i| {
+            let q = (i as f64 + 0.5) * dq;
+            *total += q * q / (q.exp() + 1.) * dq;
+            Some(*total)
+        })
         .collect();
-    let ky = kx.clone();
-    let kz = kx.clone();
+    let total = cdf[cdf.len() - 1];
+    (0..n)
+        .map(|_| {
+            let u = rng.gen::<f64>() * total;
+            let i = cdf.partition_point(|c| *c < u).min(cdf.len() - 1);
+            let lower = if i == 0 { 0. } else { cdf[i - 1] };
+            (i as f64 + (u - lower) / (cdf[i] - lower)) * dq
+        })
+        .collect()
+}
 
-    let [lx, ly, lz]: [Array3<f64>; 3] = Meshgrid3::new(&kx, &ky, &kz).pow(2).get();
-    let laplace: Array3<Complex64> = (-(lx + ly + lz)).map(|x| Complex {
-        re: {
-            if (*x).abs() > 0. {
-                *x
-            } else {
-                1e-10
+fn density_k(density: Array3<f64>) -> Array3<Complex64> {
+    fourier::forward(&density.map(|x| Complex { re: *x, im: 0. }))
+}
+
+// phi_2 of the second-order displacement (D2 / D1^2) grad phi_2
+fn second_order_potential(density_k: &Array3<Complex64>) -> Array3<Complex64> {
+    potential_k(&fourier::forward(&second_order_source(&potential_k(
+        density_k,
+    ))))
+}
+
+fn displace(
+    density: Array3<f64>,
+    order: LptOrder,
+    positions: Array2<f64>,
+) -> (Array2<f64>, Array2<f64>) {
+    let density_k: Array3<Complex64> = density_k(density);
+    let potential_2: Option<Array3<Complex64>> = match order {
+        LptOrder::First => None,
+        LptOrder::Second => Some(second_order_potential(&density_k)),
+    };
+    displace_by(&density_k, potential_2.as_ref(), positions)
+}
+
+// x = q - grad phi_1 + (D2 / D1^2) grad phi_2, with the field already evolved to A_INIT
+fn displace_by(
+    density_k: &Array3<Complex64>,
+    potential_2: Option<&Array3<Complex64>>,
+    mut positions: Array2<f64>,
+) -> (Array2<f64>, Array2<f64>) {
+    let mut velocities: Array2<f64> = Array2::zeros(positions.dim());
+    let potential_1: Array3<Complex64> = potential_k(density_k);
+
+    for i in 0..=2 {
+        let psi_1: Array3<f64> = -displacement_field_real(&potential_1, i);
+        let psi_2: Array3<f64> = match potential_2 {
+            Some(potential_2) => {
+                second_order_growth_ratio(A_INIT) * displacement_field_real(potential_2, i)
             }
-        },
-        im: 0.,
-    });
+            None => Array3::zeros(psi_1.dim()),
+        };
+        let psi_1: Array1<f64> = interpolate_field(&psi_1, &positions);
+        let psi_2: Array1<f64> = interpolate_field(&psi_2, &positions);
+        velocities
+            .slice_mut(s![i, ..])
+            .assign(&approximate_velocity(&psi_1, &psi_2));
+        positions
+            .slice_mut(s![i, ..])
+            .zip_mut_with(&(psi_1 + psi_2), |x, psi| {
+                *x = (*x + psi).rem_euclid(N_CELLS as f64)
+            });
+    }
 
-    Array3::from_shape_vec(
-        (N_PARTICLES, N_PARTICLES, N_PARTICLES),
-        Array::from_iter(density_k.iter().zip(laplace).map(|(a, b)| *a / b)).to_vec(),
-    )
-    .unwrap()
+    (positions, velocities)
 }
 
-fn zeldovich(potential_k: Array3<Complex64>, direction: usize) -> (Array1<f64>, Array1<f64>) {
-    let df = displacement_field_real(potential_k, direction);
-    (
-        approximate_positions(&df, direction),
-        approximate_velocity(&df),
-    )
+pub fn pre_initial_positions(load: ParticleLoad) -> Array2<f64> {
+    match load {
+        ParticleLoad::Lattice => lattice(),
+        ParticleLoad::Poisson => poisson(),
+        ParticleLoad::Glass => glass(poisson()),
+    }
 }
 
-// TODO use last block hash/contribution
-fn approximate_positions(displacement_field: &Array1<f64>, direction: usize) -> Array1<f64> {
-    let linear_growth_factor = D_t(A_INIT);
+fn lattice() -> Array2<f64> {
+    shifted_lattice(0.)
+}
+
+fn shifted_lattice(offset: f64) -> Array2<f64> {
+    let mut positions: Array2<f64> = Array2::zeros((3, N_PARTICLES.pow(3)));
     let mass_resolution = N_CELLS as f64 / N_PARTICLES as f64;
-    let xs = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + 0.5).to_vec();
-    let ys = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + 0.5).to_vec();
-    let zs = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + 0.5).to_vec();
+    let start = 0.5 + offset;
+    let xs = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + start).to_vec();
+    let ys = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + start).to_vec();
+    let zs = (Array::linspace(0., N_CELLS as f64 - mass_resolution, N_PARTICLES) + start).to_vec();
 
     let grid = Meshgrid3::new(&xs, &ys, &zs).get();
-    let positions = Array::from_iter(grid.get(direction).unwrap())
-        .map(|x| *x + rand::thread_rng().gen_range((-2.)..(2.)))
-        .to_vec();
+    positions
+        .rows_mut()
+        .into_iter()
+        .zip(grid.iter())
+        .for_each(|(mut row, axis)| row.assign(&Array::from_iter(axis.iter().cloned())));
+    positions
+}
 
-    // Zel'dovich Approximation causes a perturbation
-    let positions: Array1<f64> = Array::from_iter(
-        positions
-            .iter()
-            .zip(displacement_field)
-            .map(|(a, b)| (a + linear_growth_factor * b).rem_euclid(N_CELLS as f64)),
-    );
-    assert!(
-        positions.len() == N_PARTICLES.pow(3),
-        "{} != {}",
-        positions.len(),
-        N_PARTICLES.pow(3)
-    );
+// uniform random positions drawn from SEED, which also fixes the glass grown from them
+fn poisson() -> Array2<f64> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    Array2::from_shape_simple_fn((3, N_PARTICLES.pow(3)), || {
+        rng.gen_range(0.0..N_CELLS as f64)
+    })
+}
 
+// White-Frenk glass: evolve with the sign of gravity flipped, damping all motion,
+// until particles settle into a configuration with no preferred direction and
+// suppressed large-scale power.
+fn glass(mut positions: Array2<f64>) -> Array2<f64> {
+    let mass = (N_CELLS as f64 / N_PARTICLES as f64).powi(3);
+    let fgrid: Array3<f64> = ksq_inv();
+    let mut velocities: Array2<f64> = Array2::zeros(positions.dim());
+    for _ in 0..GLASS_STEPS {
+        let rho: Array3<f64> = -density(&positions, mass);
+        (positions, velocities) = update(rho, positions, velocities, &fgrid, 1., 1.);
+        velocities *= 0.5;
+    }
     positions
 }
 
-fn approximate_velocity(displacement_field: &Array1<f64>) -> Array1<f64> {
-    let dt_0 = D_t(A_INIT);
-    let h = hubble_constant(A_INIT);
-    let f = expansion_factor(A_INIT);
-    displacement_field.map(|x| A_INIT * dt_0 * h * f * x)
+// Periodic CIC interpolation of a field on an n^3 grid whose nodes share the lattice
+// origin, so any particle count can sample any field resolution.
+fn interpolate_field(field: &Array3<f64>, positions: &Array2<f64>) -> Array1<f64> {
+    let grid = field.dim().0;
+    let spacing = N_CELLS as f64 / grid as f64;
+    let n = grid as f64;
+    Array::from_iter(positions.columns().into_iter().map(|q| {
+        let u: Vec<f64> = q
+            .iter()
+            .map(|x| ((x - 0.5) / spacing).rem_euclid(n))
+            .collect();
+        let cell: Vec<usize> = u.iter().map(|x| x.floor() as usize).collect();
+        let d: Vec<f64> = u.iter().zip(&cell).map(|(x, c)| x - *c as f64).collect();
+        let mut value = 0.;
+        for corner in 0..8 {
+            let mut weight = 1.;
+            let mut idx = [0; 3];
+            for axis in 0..3 {
+                let offset = (corner >> axis) & 1;
+                idx[axis] = (cell[axis] + offset) % grid;
+                weight *= if offset == 1 { d[axis] } else { 1. - d[axis] };
+            }
+            value += weight * field[idx];
+        }
+        value
+    }))
 }
 
-fn displacement_field_k(potential_k: Array3<Complex64>, direction: usize) -> Array3<Complex<f64>> {
-    let resolution = N_CELLS as f64 / N_PARTICLES as f64;
-    let scale = 2. * PI * (N_PARTICLES as f64 / BOX_SIZE as f64);
-    let lx: Vec<f64> = sample_freq(&N_PARTICLES)
-        .iter()
-        .map(|x| x * scale)
-        .collect();
-    let ly = lx.clone();
-    let lz = lx.clone();
+/// Physical wavevectors in h/Mpc of an n^3 grid spanning BOX_SIZE.
+pub(crate) fn wavevectors(n: usize) -> [Array3<f64>; 3] {
+    let scale = 2. * PI * (n as f64 / BOX_SIZE as f64);
+    let kx: Vec<f64> = sample_freq(&n).iter().map(|x| x * scale).collect();
+    let ky = kx.clone();
+    let kz = kx.clone();
 
-    let mesh = Meshgrid3::new(&lx, &ly, &lz).get();
+    Meshgrid3::new(&kx, &ky, &kz).get()
+}
+
+// Solves laplace(phi) = source in Fourier space, dropping the k = 0 mode.
+fn potential_k(density_k: &Array3<Complex64>) -> Array3<Complex64> {
+    let [kx, ky, kz]: [Array3<f64>; 3] = wavevectors(density_k.dim().0);
+    let laplace: Array3<f64> = -(&kx * &kx + &ky * &ky + &kz * &kz);
+
+    Array3::from_shape_vec(
+        density_k.dim(),
+        Array::from_iter(density_k.iter().zip(laplace).map(|(a, b)| {
+            if b.abs() > 0. {
+                *a / b
+            } else {
+                Complex::new(0., 0.)
+            }
+        }))
+        .to_vec(),
+    )
+    .unwrap()
+}
+
+// sum_{i > j} phi_,ii phi_,jj - phi_,ij^2 from the first-order potential
+fn second_order_source(potential_k: &Array3<Complex64>) -> Array3<Complex64> {
+    let k: [Array3<f64>; 3] = wavevectors(potential_k.dim().0);
+    let hessian = |i: usize, j: usize| -> Array3<f64> {
+        let phi_ij: Array3<Complex64> = (&k[i] * &k[j]).map(|x| Complex::new(-x, 0.)) * potential_k;
+        inverse(&phi_ij).map(|x| x.re)
+    };
+    let [phi_xx, phi_yy, phi_zz] = [0, 1, 2].map(|i| hessian(i, i));
+    let [phi_xy, phi_xz, phi_yz] = [(0, 1), (0, 2), (1, 2)].map(|(i, j)| hessian(i, j));
+
+    let source: Array3<f64> = &phi_xx * &phi_yy + &phi_xx * &phi_zz + &phi_yy * &phi_zz
+        - &phi_xy * &phi_xy
+        - &phi_xz * &phi_xz
+        - &phi_yz * &phi_yz;
+    source.map(|x| Complex { re: *x, im: 0. })
+}
+
+// p = a^2 dx/dt in units of H0, i.e. a * (da/dt) * sum_n f_n psi_n
+fn approximate_velocity(psi_1: &Array1<f64>, psi_2: &Array1<f64>) -> Array1<f64> {
+    let a_dot = expansion_factor(A_INIT).recip();
+    let f_1 = growth_rate(A_INIT);
+    let f_2 = second_order_growth_rate(A_INIT);
+    (psi_1 * f_1 + psi_2 * f_2) * A_INIT * a_dot
+}
+
+// grad phi along `direction`
+fn displacement_field_k(potential_k: &Array3<Complex64>, direction: usize) -> Array3<Complex64> {
+    let mesh = wavevectors(potential_k.dim().0);
     let l_direction: &Array3<f64> = mesh.get(direction).unwrap();
-    (l_direction * potential_k).map(|x| -1. * resolution * *x * Complex::new(1., 1.))
+    l_direction.map(|x| Complex::new(0., *x)) * potential_k
 }
 
-fn displacement_field_real(potential_k: Array3<Complex64>, direction: usize) -> Array1<f64> {
+fn displacement_field_real(potential_k: &Array3<Complex64>, direction: usize) -> Array3<f64> {
     let force_resolution = N_CELLS as f64 / BOX_SIZE as f64;
     let df_k: Array3<Complex64> = displacement_field_k(potential_k, direction);
     let df_real: Array3<Complex64> = inverse(&df_k);
-    Array::from_iter(df_real.map(|x| x.re * force_resolution))
+    df_real.map(|x| x.re * force_resolution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        power_spectrum::{fundamental_mode, PowerSpectrum, Transfer},
+        random_field::{gaussian_random_field, RandomFieldOptions},
+    };
+
+    fn plane_waves(modes: &[[usize; 3]]) -> Array3<Complex64> {
+        let n = N_PARTICLES as f64;
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, k)| {
+            modes
+                .iter()
+                .map(|m| (2. * PI * (m[0] * i + m[1] * j + m[2] * k) as f64 / n).cos())
+                .sum::<f64>()
+        });
+        fourier::forward(&field.map(|x| Complex {
+            re: 0.01 * x,
+            im: 0.,
+        }))
+    }
+
+    #[test]
+    fn zeldovich_is_exact_for_plane_waves() {
+        let potential = potential_k(&plane_waves(&[[1, 0, 0]]));
+        let source = inverse(&second_order_source(&potential));
+        assert!(source.iter().all(|x| x.norm() < 1e-12));
+    }
+
+    #[test]
+    fn second_order_potential_solves_source() {
+        let potential_1 = potential_k(&plane_waves(&[[1, 0, 0], [0, 1, 0]]));
+        let source = second_order_source(&potential_1);
+        assert!(inverse(&source).iter().any(|x| x.norm() > 1e-12));
+
+        // the divergence of grad phi_2 reproduces the source
+        let potential_2 = potential_k(&fourier::forward(&source));
+        let divergence: Array3<Complex64> = (0..3)
+            .map(|i| {
+                let k = &wavevectors(N_PARTICLES)[i];
+                k.map(|x| Complex::new(0., *x)) * displacement_field_k(&potential_2, i)
+            })
+            .fold(Array3::zeros(source.dim()), |acc, x| acc + x);
+        inverse(&divergence)
+            .iter()
+            .zip(source.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10, "{} != {}", a, b));
+    }
+
+    fn count_variance(positions: &Array2<f64>) -> f64 {
+        let mut counts = Array3::<f64>::zeros((4, 4, 4));
+        let width = N_CELLS as f64 / 4.;
+        positions.columns().into_iter().for_each(|q| {
+            counts[[0, 1, 2].map(|i| (q[i] / width) as usize)] += 1.;
+        });
+        let mean = counts.mean().unwrap();
+        counts.map(|x| (x - mean).powi(2)).mean().unwrap()
+    }
+
+    #[test]
+    fn lattice_load_has_no_jitter() {
+        let zero = Array3::zeros((N_PARTICLES, N_PARTICLES, N_PARTICLES));
+        let particles = initial_conditions(zero, LptOrder::Second, ParticleLoad::Lattice);
+        assert_eq!(particles.positions, lattice());
+        assert!(particles.velocities.iter().all(|v| *v == 0.));
+        assert_eq!(particles.ids[N_PARTICLES + 1], (N_PARTICLES + 1) as u64);
+    }
+
+    #[test]
+    fn two_species_second_order_follows_total_matter() {
+        // species fields that cancel in the total matter carry no second-order term,
+        // though each alone would
+        let n = N_PARTICLES as f64;
+        let f_b = OMEGA_B0 / OMEGA_M0;
+        let cdm = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, _)| {
+            0.05 * ((2. * PI * i as f64 / n).cos() + (2. * PI * j as f64 / n).cos())
+        });
+        let baryon = &cdm * (-(1. - f_b) / f_b);
+        let run = |order| two_species_initial_conditions(cdm.clone(), baryon.clone(), order);
+        let (first, second) = (run(LptOrder::First), run(LptOrder::Second));
+        let offset = |a: &Array2<f64>, b: &Array2<f64>| (a - b).fold(0f64, |m, x| m.max(x.abs()));
+        assert!(offset(&first.positions, &second.positions) < 1e-12);
+        assert!(offset(&first.velocities, &second.velocities) < 1e-12);
+
+        let alone = |order| initial_conditions(cdm.clone(), order, ParticleLoad::Lattice);
+        let (first, second) = (alone(LptOrder::First), alone(LptOrder::Second));
+        assert!(offset(&first.positions, &second.positions) > 1e-6);
+    }
+
+    #[test]
+    fn two_species_share_phases_on_offset_lattices() {
+        let zero = Array3::zeros((N_PARTICLES, N_PARTICLES, N_PARTICLES));
+        let particles = two_species_initial_conditions(zero.clone(), zero, LptOrder::First);
+        let (positions, types) = (&particles.positions, &particles.types);
+        let n = N_PARTICLES.pow(3);
+        let spacing = N_CELLS as f64 / N_PARTICLES as f64;
+        assert!(particles.velocities.iter().all(|v| *v == 0.));
+        assert!((particles.total_mass() - n as f64 * spacing.powi(3)).abs() < 1e-9);
+        assert!(
+            (particles.mass_of(ParticleType::Baryon) / particles.total_mass()
+                - OMEGA_B0 / OMEGA_M0)
+                .abs()
+                < 1e-12
+        );
+        assert_eq!(types[0], ParticleType::DarkMatter);
+        assert_eq!(types[n], ParticleType::Baryon);
+        (0..n).for_each(|p| {
+            (0..3).for_each(|i| {
+                let offset = positions[[i, n + p]] - positions[[i, p]];
+                assert!((offset - spacing / 2.).abs() < 1e-12);
+            })
+        });
+
+        // the same noise through each transfer function recombines into total matter
+        let total = PowerSpectrum::new(Transfer::EisensteinHu);
+        let options = RandomFieldOptions::default();
+        let field = |t| gaussian_random_field(&total.with_transfer(t), &options);
+        let f_b = OMEGA_B0 / OMEGA_M0;
+        let combined = field(Transfer::EisensteinHuCdm) * (1. - f_b)
+            + field(Transfer::EisensteinHuBaryon) * f_b;
+        combined
+            .iter()
+            .zip(field(Transfer::EisensteinHu).iter())
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-12));
+    }
+
+    #[test]
+    fn neutrinos_carry_fermi_dirac_velocities() {
+        let zero = Array3::zeros((N_PARTICLES, N_PARTICLES, N_PARTICLES));
+        let neutrinos = neutrino_initial_conditions(zero, 0.1, 5);
+        let velocities = &neutrinos.velocities;
+        assert_eq!(
+            neutrinos.positions,
+            shifted_lattice(0.25 * N_CELLS as f64 / N_PARTICLES as f64)
+        );
+        assert!(neutrinos.types.iter().all(|t| *t == ParticleType::Neutrino));
+
+        // <q> = 7 pi^4 / (180 zeta(3)) = 3.1514 for the relic distribution
+        let scale =
+            neutrino_temperature() * SPEED_OF_LIGHT / 0.1 / 100. * N_CELLS as f64 / BOX_SIZE as f64;
+        let speeds: Vec<f64> = velocities
+            .columns()
+            .into_iter()
+            .map(|v| v.dot(&v).sqrt() / scale)
+            .collect();
+        let mean = speeds.iter().sum::<f64>() / speeds.len() as f64;
+        assert!((mean / 3.1514 - 1.).abs() < 0.05, "{}", mean);
+        let bulk = velocities.sum_axis(Axis(1)) / speeds.len() as f64;
+        assert!(bulk.iter().all(|v| v.abs() < 0.1 * mean * scale));
+    }
+
+    #[test]
+    fn neutrinos_free_stream_out_of_small_scales() {
+        let n = N_PARTICLES as f64;
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, _, _)| {
+            0.05 * (2. * PI * i as f64 / n).cos()
+        });
+        let largest =
+            |x: &Array2<f64>, q: &Array2<f64>| (x - q).iter().fold(0f64, |m, d| m.max(d.abs()));
+        let cdm = initial_conditions(field.clone(), LptOrder::First, ParticleLoad::Lattice);
+        let neutrinos = neutrino_initial_conditions(field, 0.1, 5);
+        let ratio = largest(
+            &neutrinos.positions,
+            &shifted_lattice(0.25 * N_CELLS as f64 / n),
+        ) / largest(&cdm.positions, &lattice());
+
+        // a quarter-spacing shift samples the same wave up to a few percent
+        let k = 2. * PI / BOX_SIZE as f64;
+        let k_fs = free_streaming_wavenumber(0.1, A_INIT);
+        let expected = (k_fs / (k + k_fs)).powi(2);
+        assert!(
+            (ratio / expected - 1.).abs() < 0.1,
+            "{} vs {}",
+            ratio,
+            expected
+        );
+    }
+
+    #[test]
+    fn displacements_interpolate_exactly_on_lattice() {
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, k)| {
+            (i * N_PARTICLES * N_PARTICLES + j * N_PARTICLES + k) as f64
+        });
+        let values = interpolate_field(&field, &lattice());
+        values
+            .iter()
+            .zip(field.iter())
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-9, "{} != {}", a, b));
+    }
+
+    #[test]
+    fn measured_power_spectrum_matches_input() {
+        // field, particle lattice and force mesh all at different resolutions
+        let spectrum = PowerSpectrum::new(Transfer::EisensteinHu);
+        let options = RandomFieldOptions {
+            grid: 2 * N_PARTICLES,
+            fixed_amplitude: true,
+            ..RandomFieldOptions::default()
+        };
+        let field = gaussian_random_field(&spectrum, &options);
+        let positions = initial_conditions(field, LptOrder::First, ParticleLoad::Lattice).positions;
+
+        // relative to the unperturbed load, as a lattice incommensurate with the mesh
+        // deposits its own harmonics onto low mesh modes
+        let mass = (N_CELLS as f64 / N_PARTICLES as f64).powi(3);
+        let delta = density(&positions, mass) - density(&lattice(), mass);
+        let delta_k = fourier::forward(&delta.map(|x| Complex::new(*x, 0.)));
+        let n: Vec<f64> = sample_freq(&N_CELLS)
+            .iter()
+            .map(|x| x * N_CELLS as f64)
+            .collect();
+        let volume = (BOX_SIZE as f64).powi(3);
+        let growth = growth_factor(A_INIT);
+        for shell in 1..=2 {
+            let (mut measured, mut expected) = (0., 0.);
+            for ((i, j, k), d) in delta_k.indexed_iter() {
+                let m = [n[i], n[j], n[k]];
+                if (m.iter().map(|x| x * x).sum::<f64>().sqrt().round() as usize) != shell {
+                    continue;
+                }
+                // CIC assignment window
+                let window: f64 = m
+                    .iter()
+                    .map(|x| {
+                        let u = PI * x / N_CELLS as f64;
+                        (u.sin() / u).powi(2)
+                    })
+                    .filter(|w| w.is_finite())
+                    .product();
+                let k_norm = m.iter().map(|x| x * x).sum::<f64>().sqrt() * fundamental_mode();
+                measured += d.norm_sqr() / window.powi(2) * volume / (N_CELLS as f64).powi(6);
+                expected += growth.powi(2) * spectrum.power(k_norm);
+            }
+            assert!(
+                (measured / expected - 1.).abs() < 0.05,
+                "shell {}: {} != {}",
+                shell,
+                measured,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn glass_suppresses_poisson_noise() {
+        let poisson = pre_initial_positions(ParticleLoad::Poisson);
+        assert_eq!(pre_initial_positions(ParticleLoad::Poisson), poisson);
+        let glass = glass(poisson.clone());
+        assert!(glass.iter().all(|x| (0.0..N_CELLS as f64).contains(x)));
+        assert!(count_variance(&glass) < 0.5 * count_variance(&poisson));
+    }
 }
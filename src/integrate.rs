@@ -1,30 +1,36 @@
 use ndarray::{s, Array1, Array2, Array3, ArrayViewMut1, Axis};
-use rand_distr::num_traits::ToPrimitive;
 
-use crate::{config::N_CELLS, cosmology::expansion_factor, potential::potential};
+use rayon::prelude::*;
 
-pub fn update(
-    density: Array3<f64>,
-    positions: Array2<f64>,
-    velocities: Array2<f64>,
-    fgrid: &Array3<f64>,
+use crate::{config::N_CELLS, cosmology::expansion_factor, float::Float, potential::potential};
+
+// particles per rayon task
+const MIN_CHUNK: usize = 4096;
+
+/// Kick-drift step of every particle. Arrays are stored in `F`, while the time, the
+/// CIC-weighted gradient and the new phase-space coordinates are computed in `f64`.
+pub fn update<F: Float>(
+    density: Array3<F>,
+    positions: Array2<F>,
+    velocities: Array2<F>,
+    fgrid: &Array3<F>,
     t: f64,
     dt: f64,
-) -> (Array2<f64>, Array2<f64>) {
-    let potentials: Array3<f64> = potential(density, fgrid, t);
+) -> (Array2<F>, Array2<F>) {
+    let potentials: Array3<F> = potential(density, fgrid, t);
     let f_a = expansion_factor(t);
     integrate(positions, velocities, potentials, t, f_a, dt)
 }
 
-fn integrate(
-    mut positions: Array2<f64>,
-    mut velocities: Array2<f64>,
-    potentials: Array3<f64>,
+fn integrate<F: Float>(
+    mut positions: Array2<F>,
+    mut velocities: Array2<F>,
+    potentials: Array3<F>,
     t: f64,
     f_a: f64,
     dt: f64,
-) -> (Array2<f64>, Array2<f64>) {
-    let centered_cells: Array2<f64> = positions.map(|x| x.floor());
+) -> (Array2<F>, Array2<F>) {
+    let centered_cells: Array2<F> = positions.map(|x| x.floor());
     let weights = cic_weights(&positions, &centered_cells);
 
     for i in 0..=2 {
@@ -45,93 +51,228 @@ fn integrate(
     (positions, velocities)
 }
 
-fn cic_weights(positions: &Array2<f64>, centered_cells: &Array2<f64>) -> Array2<f64> {
+fn cic_weights<F: Float>(positions: &Array2<F>, centered_cells: &Array2<F>) -> Array2<F> {
     let len = positions.len_of(Axis(1));
-    let mut t: Array2<f64> = Array2::zeros((8, len));
-    let diff: Array2<f64> = positions - centered_cells;
-    for i in 0..len {
-        let dx = diff.get((0, i)).unwrap();
-        let dy = diff.get((1, i)).unwrap();
-        let dz = diff.get((2, i)).unwrap();
-
-        let [tx, ty, tz] = [dx, dy, dz].map(|x| 1. - x);
-        t.slice_mut(s![0, i]).fill(tx * ty * tz);
-        t.slice_mut(s![1, i]).fill(dx * ty * tz);
-        t.slice_mut(s![2, i]).fill(tx * dy * tz);
-        t.slice_mut(s![3, i]).fill(tx * ty * dz);
-        t.slice_mut(s![4, i]).fill(dx * dx * tz);
-        t.slice_mut(s![5, i]).fill(dx * ty * dz);
-        t.slice_mut(s![6, i]).fill(tx * dy * dz);
-        t.slice_mut(s![7, i]).fill(dx * dy * dz);
-    }
+    let diff: Array2<F> = positions - centered_cells;
+    let weights: Vec<[F; 8]> = (0..len)
+        .into_par_iter()
+        .with_min_len(MIN_CHUNK)
+        .map(|i| {
+            let dx = diff[[0, i]].f64();
+            let dy = diff[[1, i]].f64();
+            let dz = diff[[2, i]].f64();
 
-    t
+            let [tx, ty, tz] = [dx, dy, dz].map(|x| 1. - x);
+            [
+                tx * ty * tz,
+                dx * ty * tz,
+                tx * dy * tz,
+                tx * ty * dz,
+                dx * dy * tz,
+                dx * ty * dz,
+                tx * dy * dz,
+                dx * dy * dz,
+            ]
+            .map(F::of)
+        })
+        .collect();
+
+    Array2::from_shape_vec((len, 8), weights.concat())
+        .unwrap()
+        .reversed_axes()
 }
 
-fn interpolate(
-    positions: &ArrayViewMut1<f64>,
-    velocities: &ArrayViewMut1<f64>,
-    centered_cells: &Array2<f64>,
-    potentials: &Array3<f64>,
-    cic_weights: &Array2<f64>,
+#[allow(clippy::too_many_arguments, non_snake_case)]
+fn interpolate<F: Float>(
+    positions: &ArrayViewMut1<F>,
+    velocities: &ArrayViewMut1<F>,
+    centered_cells: &Array2<F>,
+    potentials: &Array3<F>,
+    cic_weights: &Array2<F>,
     f_a: &f64,
     t: &f64,
     dt: &f64,
     axis: i32,
-) -> (Array1<f64>, Array1<f64>) {
-    let mut cc_p: Array2<f64> = centered_cells.clone();
-    let mut cc_n: Array2<f64> = centered_cells.clone();
+) -> (Array1<F>, Array1<F>) {
+    let mut cc_p: Array2<F> = centered_cells.clone();
+    let mut cc_n: Array2<F> = centered_cells.clone();
     let n_cells = N_CELLS as f64;
 
     cc_p.slice_mut(s![axis, ..]).iter_mut().for_each(|x| {
-        *x = *x + 1.;
+        *x += F::one();
     });
 
     cc_n.slice_mut(s![axis, ..]).iter_mut().for_each(|x| {
-        *x = *x - 1.;
+        *x -= F::one();
     });
 
-    let cc_p: Array2<f64> = cc_p.map(|x| x.rem_euclid(n_cells));
-    let cc_n: Array2<f64> = cc_n.map(|x| x.rem_euclid(n_cells));
+    let cc_p: Array2<usize> = cc_p.map(|x| x.f64().rem_euclid(n_cells) as usize);
+    let cc_n: Array2<usize> = cc_n.map(|x| x.f64().rem_euclid(n_cells) as usize);
 
     let len = positions.len_of(Axis(0));
-    let mut next_velocities: Array1<f64> = Array1::zeros(len);
-    let mut next_positions: Array1<f64> = Array1::zeros(len);
-
-    for i in 0..len {
-        let x1 = *(cc_n.get((0, i)).unwrap()) as usize;
-        let y1 = *(cc_n.get((1, i)).unwrap()) as usize;
-        let z1 = *(cc_n.get((2, i)).unwrap()) as usize;
-
-        let x2 = *(cc_p.get((0, i)).unwrap()) as usize;
-        let y2 = *(cc_p.get((1, i)).unwrap()) as usize;
-        let z2 = *(cc_p.get((2, i)).unwrap()) as usize;
-
-        let [X1, Y1, Z1] = [x1, y1, z1].map(|x| (x + 1).rem_euclid(N_CELLS));
-        let [X2, Y2, Z2] = [x2, y2, z2].map(|x| (x + 1).rem_euclid(N_CELLS));
-
-        let weight = cic_weights.slice(s![.., i]);
-        let g = potentials.get((z2, y2, x2)).unwrap() - potentials.get((z1, y1, x1)).unwrap();
-        let g_x: f64 =
-            potentials.get((z2, y2, X2)).unwrap() - potentials.get((z1, y1, X1)).unwrap();
-        let g_y = potentials.get((z2, Y2, x2)).unwrap() - potentials.get((z1, Y1, x1)).unwrap();
-        let g_z = potentials.get((Z2, y2, x2)).unwrap() - potentials.get((Z1, y1, x1)).unwrap();
-        let g_xy = potentials.get((z2, Y2, X2)).unwrap() - potentials.get((z1, Y1, X1)).unwrap();
-        let g_xz = potentials.get((Z2, y2, X2)).unwrap() - potentials.get((Z1, y1, X1)).unwrap();
-        let g_yz = potentials.get((Z2, Y2, x2)).unwrap() - potentials.get((Z1, Y1, x1)).unwrap();
-        let g_xyz = potentials.get((Z2, Y2, X2)).unwrap() - potentials.get((Z1, Y1, X1)).unwrap();
-        let g_p = [g, g_x, g_y, g_z, g_xy, g_xz, g_yz, g_xyz]
-            .iter()
-            .zip(weight)
-            .fold(0., |sum, x| sum + x.0 * x.1)
-            / 2.;
-
-        let v = velocities.get(i).unwrap();
-        let x = positions.get(i).unwrap();
-        next_velocities.slice_mut(s![i]).fill(v + dt * f_a * g_p);
-        next_positions
-            .slice_mut(s![i])
-            .fill((x + dt * v / (t + dt).powi(2) * f_a).rem_euclid(n_cells));
+    let (next_positions, next_velocities): (Vec<F>, Vec<F>) = (0..len)
+        .into_par_iter()
+        .with_min_len(MIN_CHUNK)
+        .map(|i| {
+            let x1 = cc_n[[0, i]];
+            let y1 = cc_n[[1, i]];
+            let z1 = cc_n[[2, i]];
+
+            let x2 = cc_p[[0, i]];
+            let y2 = cc_p[[1, i]];
+            let z2 = cc_p[[2, i]];
+
+            let [X1, Y1, Z1] = [x1, y1, z1].map(|x| (x + 1).rem_euclid(N_CELLS));
+            let [X2, Y2, Z2] = [x2, y2, z2].map(|x| (x + 1).rem_euclid(N_CELLS));
+
+            let weight = cic_weights.slice(s![.., i]);
+            let potentials = |i| potentials[i].f64();
+            let g = potentials([z2, y2, x2]) - potentials([z1, y1, x1]);
+            let g_x = potentials([z2, y2, X2]) - potentials([z1, y1, X1]);
+            let g_y = potentials([z2, Y2, x2]) - potentials([z1, Y1, x1]);
+            let g_z = potentials([Z2, y2, x2]) - potentials([Z1, y1, x1]);
+            let g_xy = potentials([z2, Y2, X2]) - potentials([z1, Y1, X1]);
+            let g_xz = potentials([Z2, y2, X2]) - potentials([Z1, y1, X1]);
+            let g_yz = potentials([Z2, Y2, x2]) - potentials([Z1, Y1, x1]);
+            let g_xyz = potentials([Z2, Y2, X2]) - potentials([Z1, Y1, X1]);
+            let g_p = [g, g_x, g_y, g_z, g_xy, g_xz, g_yz, g_xyz]
+                .iter()
+                .zip(weight)
+                .fold(0., |sum, x| sum + x.0 * x.1.f64())
+                / 2.;
+
+            let v = velocities[i].f64();
+            let x = positions[i].f64();
+            // g_p is the potential gradient, gravity pulls the other way
+            (
+                F::of((x + dt * v / (t + dt).powi(2) * f_a).rem_euclid(n_cells)),
+                F::of(v - dt * f_a * g_p),
+            )
+        })
+        .unzip();
+    (Array1::from(next_positions), Array1::from(next_velocities))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{density::density, fourier::ksq_inv};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rayon::ThreadPoolBuilder;
+
+    #[test]
+    fn gravity_is_attractive() {
+        // a point mass in the middle of the box, test particles at rest on either side
+        // of it along each axis
+        let c = (N_CELLS / 2) as f64;
+        let mut density = Array3::zeros((N_CELLS, N_CELLS, N_CELLS));
+        density[[N_CELLS / 2, N_CELLS / 2, N_CELLS / 2]] = 1000.;
+        let offsets = [-3., 3.];
+        let mut positions = Array2::from_elem((3, 6), c);
+        for axis in 0..3 {
+            for (side, offset) in offsets.iter().enumerate() {
+                positions[[axis, 2 * axis + side]] += offset;
+            }
+        }
+        let velocities = Array2::zeros((3, 6));
+
+        let (_, velocities) = update(density, positions, velocities, &ksq_inv(), 1., 0.01);
+        for axis in 0..3 {
+            for (side, offset) in offsets.iter().enumerate() {
+                let v = velocities[[axis, 2 * axis + side]];
+                assert!(v * offset < 0., "axis {} side {}: v = {}", axis, side, v);
+            }
+        }
+    }
+
+    #[test]
+    fn cic_weights_are_a_partition_of_unity() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let positions =
+            Array2::from_shape_simple_fn((3, 100), || rng.gen_range(0.0..N_CELLS as f64));
+        let cells = positions.map(|x: &f64| x.floor());
+        let weights = cic_weights(&positions, &cells);
+        // corners in the order of the gradients in `interpolate`
+        let corners = [
+            [0., 0., 0.],
+            [1., 0., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [1., 1., 0.],
+            [1., 0., 1.],
+            [0., 1., 1.],
+            [1., 1., 1.],
+        ];
+        for i in 0..positions.len_of(Axis(1)) {
+            let w = weights.column(i);
+            assert!((w.sum() - 1.).abs() < 1e-12);
+            for axis in 0..3 {
+                let centroid: f64 = corners.iter().zip(&w).map(|(c, w)| c[axis] * w).sum();
+                let d = positions[[axis, i]] - cells[[axis, i]];
+                assert!((centroid - d).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn update_is_independent_of_thread_count() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let n = 3 * MIN_CHUNK;
+        let positions = Array2::from_shape_simple_fn((3, n), || rng.gen_range(0.0..N_CELLS as f64));
+        let velocities = Array2::from_shape_simple_fn((3, n), || rng.gen_range(-1.0..1.));
+        let rho = density(&positions, 1.);
+        let fgrid = ksq_inv();
+
+        let run = |threads| {
+            ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    update(
+                        rho.clone(),
+                        positions.clone(),
+                        velocities.clone(),
+                        &fgrid,
+                        0.5,
+                        0.01,
+                    )
+                })
+        };
+        assert_eq!(run(1), run(4));
+    }
+
+    #[test]
+    fn single_precision_step_tracks_double() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let n = 2000;
+        let positions = Array2::from_shape_simple_fn((3, n), || rng.gen_range(0.0..N_CELLS as f64));
+        let velocities = Array2::from_shape_simple_fn((3, n), || rng.gen_range(-1.0..1.));
+        let single = |x: &Array2<f64>| x.mapv(|x| x as f32);
+
+        let (x64, v64) = update(
+            density(&positions, 1.),
+            positions.clone(),
+            velocities.clone(),
+            &ksq_inv(),
+            0.5,
+            0.01,
+        );
+        let (x32, v32) = update(
+            density(&single(&positions), 1.),
+            single(&positions),
+            single(&velocities),
+            &ksq_inv().mapv(|x| x as f32),
+            0.5,
+            0.01,
+        );
+        let n_cells = N_CELLS as f64;
+        x64.iter().zip(&x32).for_each(|(a, b)| {
+            let d = (a - *b as f64).rem_euclid(n_cells);
+            assert!(d.min(n_cells - d) < 1e-4, "{} vs {}", a, b);
+        });
+        v64.iter()
+            .zip(&v32)
+            .for_each(|(a, b)| assert!((a - *b as f64).abs() < 1e-4 * a.abs().max(1.)));
     }
-    (next_positions, next_velocities)
 }
@@ -1,11 +1,26 @@
+pub mod checkpoint;
 pub mod config;
+pub mod constrained;
 pub mod cosmology;
+pub mod curve;
 pub mod density;
+pub mod float;
 pub mod fourier;
+pub mod gadget;
+pub mod healpix;
 pub mod ic;
 pub mod integrate;
+pub mod lightcone;
 pub mod meshgrid;
+pub mod npy;
 pub mod particle_mesh;
+pub mod particles;
 pub mod potential;
+pub mod power_spectrum;
 pub mod random_field;
+pub mod schedule;
+pub mod sky_map;
+pub mod tabulated;
 pub mod utils;
+pub mod vtk;
+pub mod zoom;
@@ -0,0 +1,229 @@
+use std::{io::Result, path::Path};
+
+use ndarray::{Array1, Array2};
+use rayon::prelude::*;
+
+use crate::{
+    config::{BOX_SIZE, N_CELLS},
+    cosmology::comoving_distance,
+    float::Float,
+    npy::{particle_arrays, to_npy, write_npz},
+    particles::Particles,
+};
+
+/// Past lightcone of an observer at rest at `observer`, in cells, out to `z_max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lightcone {
+    pub observer: [f64; 3],
+    /// Earliest scale factor recorded
+    pub a_min: f64,
+}
+
+/// Particles that crossed the lightcone during one step, at their interpolated
+/// positions in cells from the box origin, outside the box for periodic replicas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crossings {
+    pub particles: Particles,
+    /// Scale factor at which each particle crossed
+    pub scale_factors: Array1<f64>,
+}
+
+// one particle image crossing, before gathering into a Particles store
+struct Crossing {
+    p: usize,
+    x: [f64; 3],
+    v: [f64; 3],
+    a: f64,
+}
+
+impl Lightcone {
+    pub fn new(observer: [f64; 3], z_max: f64) -> Lightcone {
+        Lightcone {
+            observer,
+            a_min: 1. / (1. + z_max),
+        }
+    }
+
+    // lightcone radius at `a` in cells
+    fn radius(&self, a: f64) -> f64 {
+        comoving_distance(a) * N_CELLS as f64 / BOX_SIZE as f64
+    }
+
+    // offsets of the periodic images of the box reaching the shell between the radii
+    fn replicas(&self, inner: f64, outer: f64) -> Vec<[f64; 3]> {
+        let n = N_CELLS as f64;
+        let o = self.observer;
+        let range =
+            |i: usize| ((o[i] - outer) / n).floor() as i64..=((o[i] + outer) / n).floor() as i64;
+        let mut replicas = vec![];
+        for i in range(0) {
+            for j in range(1) {
+                for k in range(2) {
+                    let r = [i, j, k].map(|x| x as f64 * n);
+                    let near = (0..3)
+                        .map(|a| (r[a] - o[a]).max(o[a] - r[a] - n).max(0.).powi(2))
+                        .sum::<f64>()
+                        .sqrt();
+                    let far = (0..3)
+                        .map(|a| (r[a] - o[a]).abs().max((r[a] + n - o[a]).abs()).powi(2))
+                        .sum::<f64>()
+                        .sqrt();
+                    if near <= outer && far >= inner {
+                        replicas.push(r);
+                    }
+                }
+            }
+        }
+        replicas
+    }
+
+    /// Particles crossing the lightcone between `before` at `a_1` and `after` at `a_2`,
+    /// the same particles in the same order one step later. Positions, velocities and
+    /// the lightcone radius are interpolated linearly over the step, and every
+    /// periodic replica of the box is searched. The crossings are kept in `f64`
+    /// whatever the storage precision.
+    pub fn crossings<F: Float>(
+        &self,
+        before: &Particles<F>,
+        after: &Particles<F>,
+        a_1: f64,
+        a_2: f64,
+    ) -> Crossings {
+        let (outer, inner) = (self.radius(a_1.max(self.a_min)), self.radius(a_2).max(0.));
+        let empty = a_2 <= self.a_min || outer <= 0.;
+        let replicas = if empty {
+            vec![]
+        } else {
+            self.replicas(inner, outer)
+        };
+        let (chi_1, chi_2) = (self.radius(a_1), self.radius(a_2));
+        let n = N_CELLS as f64;
+        let wrap = |dx: f64| (dx + n / 2.).rem_euclid(n) - n / 2.;
+        let distance = |x: [f64; 3]| {
+            (0..3)
+                .map(|i| (x[i] - self.observer[i]).powi(2))
+                .sum::<f64>()
+                .sqrt()
+        };
+
+        let found: Vec<Crossing> = (0..before.len())
+            .into_par_iter()
+            .flat_map_iter(|p| {
+                let x_1 = [0, 1, 2].map(|i| before.positions[[i, p]].f64());
+                let dx = [0, 1, 2].map(|i| wrap(after.positions[[i, p]].f64() - x_1[i]));
+                replicas.iter().filter_map(move |r| {
+                    let start = [0, 1, 2].map(|i| x_1[i] + r[i]);
+                    let end = [0, 1, 2].map(|i| start[i] + dx[i]);
+                    // inside the lightcone at the start of the step, outside at the end
+                    let (f_1, f_2) = (distance(start) - chi_1, distance(end) - chi_2);
+                    if f_1 > 0. || f_2 <= 0. {
+                        return None;
+                    }
+                    let s = f_1 / (f_1 - f_2);
+                    let a = a_1 + s * (a_2 - a_1);
+                    if a < self.a_min {
+                        return None;
+                    }
+                    let v = [0, 1, 2].map(|i| {
+                        let v_1 = before.velocities[[i, p]].f64();
+                        let v_2 = after.velocities[[i, p]].f64();
+                        v_1 + s * (v_2 - v_1)
+                    });
+                    let x = [0, 1, 2].map(|i| start[i] + s * dx[i]);
+                    Some(Crossing { p, x, v, a })
+                })
+            })
+            .collect();
+
+        let columns = |f: fn(&Crossing) -> [f64; 3]| {
+            Array2::from_shape_fn((3, found.len()), |(i, c)| f(&found[c])[i])
+        };
+        Crossings {
+            particles: Particles {
+                ids: found.iter().map(|c| before.ids[c.p]).collect(),
+                positions: columns(|c| c.x),
+                velocities: columns(|c| c.v),
+                masses: found.iter().map(|c| before.masses[c.p].f64()).collect(),
+                types: found.iter().map(|c| before.types[c.p]).collect(),
+            },
+            scale_factors: found.iter().map(|c| c.a).collect(),
+        }
+    }
+}
+
+/// One lightcone shell as `.npz`, the particle arrays of `npy::particle_arrays` and
+/// the crossing scale factors as `a`.
+pub fn write_crossings(path: impl AsRef<Path>, crossings: &Crossings) -> Result<()> {
+    let mut arrays = particle_arrays(&crossings.particles);
+    arrays.push(("a", to_npy(&crossings.scale_factors)));
+    write_npz(path, &arrays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particles::ParticleType;
+    use ndarray::array;
+
+    fn particles(x: Array2<f64>) -> Particles {
+        let n = x.ncols();
+        Particles::new(x, Array2::zeros((3, n)), 1., ParticleType::DarkMatter)
+    }
+
+    #[test]
+    fn particles_cross_once_in_every_replica() {
+        let n = N_CELLS as f64;
+        let cone = Lightcone::new([0.; 3], 10.);
+        // a step over which the radius shrinks by a few box lengths
+        let (a_1, a_2) = (0.99, 0.995);
+        let (chi_1, chi_2) = (cone.radius(a_1), cone.radius(a_2));
+        assert!(chi_1 - chi_2 > 2. * n);
+
+        // a particle at rest is seen once per image in the shell, each at the time
+        // the radius reaches it
+        let still = particles(array![[1.], [2.], [3.]]);
+        let crossings = cone.crossings(&still, &still, a_1, a_2);
+        assert!(!crossings.particles.is_empty());
+        for (c, a) in crossings.scale_factors.iter().enumerate() {
+            let x = crossings.particles.positions.column(c);
+            let r = x.dot(&x).sqrt();
+            assert!(r > chi_2 && r <= chi_1);
+            let chi = chi_1 + (a - a_1) / (a_2 - a_1) * (chi_2 - chi_1);
+            assert!((r - chi).abs() < 1e-9);
+            [1., 2., 3.]
+                .iter()
+                .zip(x)
+                .for_each(|(x, y)| assert!((y - x).rem_euclid(n).abs() < 1e-9));
+        }
+        let mut images: Vec<_> = crossings
+            .particles
+            .positions
+            .columns()
+            .into_iter()
+            .map(|x| x.map(|x| (x / n).floor() as i64).to_vec())
+            .collect();
+        images.sort();
+        images.dedup();
+        assert_eq!(images.len(), crossings.particles.len());
+
+        // a particle moving across the periodic boundary keeps a continuous path
+        let before = particles(array![[n - 0.25], [0.5], [0.5]]);
+        let after = particles(array![[0.25], [0.5], [0.5]]);
+        let moving = cone.crossings(&before, &after, a_1, a_2);
+        assert!(!moving.particles.is_empty());
+        assert!(moving
+            .particles
+            .positions
+            .row(0)
+            .iter()
+            .all(|x| (x.rem_euclid(n) - n / 2.).abs() >= n / 2. - 0.25 - 1e-9));
+
+        // nothing past z_max or in the future
+        let near = Lightcone::new([0.; 3], 0.001);
+        assert!(near
+            .crossings(&still, &still, a_1, a_2)
+            .particles
+            .is_empty());
+        assert!(cone.crossings(&still, &still, 1., 1.1).particles.is_empty());
+    }
+}
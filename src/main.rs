@@ -1,46 +1,130 @@
-use config::*;
 use nbody::{
-    density::density,
+    checkpoint::{config_summary, restart, write_checkpoint, Checkpoint},
+    config::*,
+    constrained::constrained_random_field,
+    cosmology::neutrino_fraction,
+    float::{Float, Precision},
     fourier::ksq_inv,
-    ic::initial_conditions,
+    gadget::write_gadget,
+    healpix::Ordering,
+    ic::{
+        initial_conditions, neutrino_initial_conditions, two_species_initial_conditions,
+        ParticleLoad,
+    },
     integrate::update,
-    random_field::gaussian_random_field,
-    utils::{array_2_to_image, array_3_to_image, hist},
+    lightcone::{write_crossings, Lightcone},
+    npy::{particle_arrays, to_npy, write_npy, write_npz},
+    particles::Particles,
+    potential::potential,
+    power_spectrum::{PowerSpectrum, Transfer},
+    random_field::{gaussian_random_field, RandomFieldOptions},
+    schedule::{snapshot_name, Schedule},
+    sky_map::{write_fits, Shells},
+    tabulated::{read_camb_transfer, read_class_pk},
+    utils::array_3_to_image,
+    vtk::{write_vti, write_vtp},
+    zoom::zoom,
 };
-use ndarray::{s, Array2, Array3};
-
-mod config;
-mod particle_mesh;
+use ndarray::Array3;
 
 fn main() {
-    let particle_mass = 1.32
-        * 1e5
-        * (OMEGA_M0 * H0.powi(2))
-        * (BOX_SIZE as f64 / (N_PARTICLES as f64 / 128.)).powi(3);
-    let average_density = (N_CELLS as f64 / N_PARTICLES as f64).powi(3);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(THREADS)
+        .build_global()
+        .unwrap();
+    match PRECISION {
+        Precision::Single => run::<f32>(),
+        Precision::Double => run::<f64>(),
+    }
+}
+
+/// The main loop, with the particles and meshes stored in `F`.
+fn run<F: Float>() {
     let dt = (A_END - A_INIT) / STEPS;
-    let dt_plot = (A_END - A_INIT) / N_PLOTS;
-    let mut t_current = A_INIT;
-
-    let mut n_plots = 0.;
-    let rho = gaussian_random_field(POWER, AMPLITUDE);
-    let (mut positions, mut velocities): (Array2<f64>, Array2<f64>) =
-        initial_conditions(rho.clone());
-    let ksq_inverse: Array3<f64> = ksq_inv();
-    let mut idx = 0;
-    while t_current < A_END - dt {
-        let rho: Array3<f64> = density(&positions, average_density);
+
+    let (mut particles, mut t_current, mut idx, mut n_plots, mut shells) = if std::env::args()
+        .any(|arg| arg == "--restart")
+    {
+        let checkpoint = restart(CHECKPOINT_FILE).expect("cannot restart");
+        println!("restarting from step {}", checkpoint.step);
+        (
+            checkpoint.particles.cast(),
+            checkpoint.time,
+            checkpoint.step,
+            checkpoint.plots,
+            checkpoint.shells,
+        )
+    } else {
+        let shells = LIGHTCONE_OBSERVER
+            .map(|observer| Shells::new(observer, SKY_MAP_SHELLS, SKY_MAP_NSIDE, Ordering::Ring));
+        (initial_particles().cast(), A_INIT, 0, 0, shells)
+    };
+    let mut schedule = Schedule::new(OUTPUT_TIMES, t_current, A_END);
+    let lightcone = LIGHTCONE_OBSERVER.map(|observer| Lightcone::new(observer, LIGHTCONE_Z_MAX));
+    let ksq_inverse: Array3<F> = ksq_inv().mapv(F::of);
+    while !schedule.finished(t_current) {
+        if SORT_EVERY > 0 && idx % SORT_EVERY == 0 {
+            particles.sort_along(SORT_CURVE);
+        }
+        let rho: Array3<F> = particles.density();
         let _rho = rho.clone();
-        (positions, velocities) = update(rho, positions, velocities, &ksq_inverse, t_current, dt);
-        t_current += dt;
+        let t_next = schedule.next_time(t_current, dt);
+        let before = lightcone.map(|_| particles.clone());
+        (particles.positions, particles.velocities) = update(
+            rho,
+            particles.positions,
+            particles.velocities,
+            &ksq_inverse,
+            t_current,
+            t_next - t_current,
+        );
+        let t_previous = t_current;
+        t_current = t_next;
         idx += 1;
 
-        if t_current >= A_INIT + n_plots * dt_plot {
-            println!("saving {}", idx);
-            println!("{:?}", _rho);
+        if let (Some(lightcone), Some(before)) = (lightcone, before) {
+            let shell = lightcone.crossings(&before, &particles, t_previous, t_current);
+            if let Some(shells) = &mut shells {
+                shells.add(&shell);
+            }
+            if !shell.particles.is_empty() {
+                let path = format!("./lightcone_{:05}.npz", idx);
+                write_crossings(path, &shell).expect("cannot write lightcone shell");
+            }
+        }
+
+        if schedule.output_due(t_current) {
+            let name = snapshot_name(t_current);
+            println!("saving {} at step {}", name, idx);
+            // outputs are written in f64 whatever the storage precision
+            let phi = (NPZ_OUTPUT || VTK_OUTPUT)
+                .then(|| potential(_rho.clone(), &ksq_inverse, t_previous).mapv(F::f64));
+            let (particles, _rho): (Particles, _) = (particles.cast(), _rho.mapv(F::f64));
             // save density
             let img = array_3_to_image(_rho.map(|x| (*x * 5.) as u8), Some(N_CELLS));
-            let _ = img.save(format!("./img/positions_small/d{}.png", idx));
+            let _ = img.save(format!("./img/positions_small/{}.png", name));
+
+            if let Some(format) = GADGET_FORMAT {
+                let path = format!("./{}", name);
+                write_gadget(path, &particles, t_current, format).expect("cannot write snapshot");
+            }
+            if let (true, Some(phi)) = (NPZ_OUTPUT, &phi) {
+                let mut arrays = particle_arrays(&particles);
+                arrays.push(("density", to_npy(&_rho)));
+                arrays.push(("potential", to_npy(phi)));
+                let path = format!("./{}.npz", name);
+                write_npz(path, &arrays).expect("cannot write arrays");
+            }
+            if let (true, Some(phi)) = (VTK_OUTPUT, &phi) {
+                let path = format!("./{}", name);
+                write_vtp(format!("{}.vtp", path), &particles, t_current)
+                    .expect("cannot write vtp");
+                write_vti(
+                    format!("{}.vti", path),
+                    &[("density", &_rho), ("potential", phi)],
+                )
+                .expect("cannot write vti");
+            }
 
             // save position
             // let img = array_2_to_image(positions.clone(), N_CELLS);
@@ -56,7 +140,106 @@ fn main() {
             //         .collect::<Vec<u32>>();
             //     hist(a, Some(format!("positions_small/v_{}_{}", idx, c)));
             // });
-            n_plots += 1.;
+            n_plots += 1;
+        }
+
+        if CHECKPOINT_EVERY > 0 && idx % CHECKPOINT_EVERY == 0 {
+            let checkpoint = Checkpoint {
+                particles: particles.cast(),
+                time: t_current,
+                step: idx,
+                plots: n_plots,
+                seed: SEED,
+                config: config_summary(),
+                shells: shells.clone(),
+            };
+            write_checkpoint(CHECKPOINT_FILE, &checkpoint).expect("cannot write checkpoint");
+        }
+    }
+
+    if let Some(shells) = shells {
+        for (shell, map) in shells.maps.iter().enumerate() {
+            let (z_min, z_max) = shells.redshifts(shell);
+            let name = format!("./skymap_z{:.3}-{:.3}", z_min, z_max);
+            write_fits(format!("{}.fits", name), map, (z_min, z_max))
+                .expect("cannot write sky map");
+            let c_l = map.overdensity().angular_power_spectrum(3 * map.nside - 1);
+            write_npy(format!("{}_cl.npy", name), &ndarray::Array1::from(c_l))
+                .expect("cannot write angular power spectrum");
+        }
+    }
+}
+
+/// Particles at A_INIT, from the white noise of SEED.
+fn initial_particles() -> Particles {
+    if TWO_SPECIES {
+        reject(
+            "TWO_SPECIES uses the Eisenstein-Hu species transfers on a plain lattice",
+            &[
+                (!CONSTRAINTS.is_empty(), "CONSTRAINTS"),
+                (PARTICLE_LOAD != ParticleLoad::Lattice, "PARTICLE_LOAD"),
+                (ZOOM_REGION.is_some(), "ZOOM_REGION"),
+                (POWER_SPECTRUM_FILE.is_some(), "POWER_SPECTRUM_FILE"),
+                (TRANSFER_FILE.is_some(), "TRANSFER_FILE"),
+                (TRANSFER != Transfer::EisensteinHu, "TRANSFER"),
+                (WDM_MASS.is_some(), "WDM_MASS"),
+                (NEUTRINO_MASS.is_some(), "NEUTRINO_MASS"),
+            ],
+        );
+        let spectrum = PowerSpectrum::new(TRANSFER);
+        let options = RandomFieldOptions::default();
+        let species = |transfer| gaussian_random_field(&spectrum.with_transfer(transfer), &options);
+        return two_species_initial_conditions(
+            species(Transfer::EisensteinHuCdm),
+            species(Transfer::EisensteinHuBaryon),
+            LPT_ORDER,
+        );
+    }
+
+    let mut spectrum = match (POWER_SPECTRUM_FILE, TRANSFER_FILE) {
+        (Some(_), Some(_)) => panic!("set at most one of POWER_SPECTRUM_FILE and TRANSFER_FILE"),
+        (Some(path), None) => {
+            PowerSpectrum::from_power_table(read_class_pk(path).expect("cannot read P(k)"))
         }
+        (None, Some(path)) => PowerSpectrum::new(Transfer::Table(
+            read_camb_transfer(path).expect("cannot read transfer function"),
+        )),
+        (None, None) => PowerSpectrum::new(TRANSFER),
+    };
+    if let Some(mass) = WDM_MASS {
+        spectrum = spectrum.with_wdm(mass);
+    }
+    let rho = constrained_random_field(&spectrum, &RandomFieldOptions::default(), CONSTRAINTS);
+    let mut particles: Particles = initial_conditions(rho.clone(), LPT_ORDER, PARTICLE_LOAD);
+    if let Some(region) = ZOOM_REGION {
+        reject(
+            "ZOOM_REGION selects and merges particles by their lattice site",
+            &[(PARTICLE_LOAD != ParticleLoad::Lattice, "PARTICLE_LOAD")],
+        );
+        particles = zoom(
+            &particles,
+            PARTICLE_LOAD,
+            &region,
+            ZOOM_LEVELS,
+            ZOOM_SHELL_WIDTH,
+        );
     }
+    if let Some(mass) = NEUTRINO_MASS {
+        reject(
+            "NEUTRINO_MASS puts its particles on the whole unzoomed lattice",
+            &[(ZOOM_REGION.is_some(), "ZOOM_REGION")],
+        );
+        particles.masses *= 1. - neutrino_fraction(mass);
+        particles.append(neutrino_initial_conditions(rho.clone(), mass, SEED));
+    }
+    particles
+}
+
+// Refuses to start while any of the named options that `reason` rules out is set.
+fn reject(reason: &str, options: &[(bool, &str)]) {
+    let set: Vec<&str> = options
+        .iter()
+        .filter_map(|(set, name)| set.then_some(*name))
+        .collect();
+    assert!(set.is_empty(), "{}; unset {}", reason, set.join(", "));
 }
@@ -9,11 +9,10 @@ pub struct Meshgrid2 {
 }
 
 impl Meshgrid2 {
-    pub fn new(xs: &Vec<f64>, ys: &Vec<f64>) -> Meshgrid2 {
+    pub fn new(xs: &[f64], ys: &[f64]) -> Meshgrid2 {
         let grid: Vec<(f64, f64)> = ys
-            .clone()
             .iter()
-            .flat_map(move |y| xs.clone().iter().map(move |x| (*x, *y)).collect::<Vec<_>>())
+            .flat_map(move |y| xs.iter().map(move |x| (*x, *y)).collect::<Vec<_>>())
             .collect();
         Meshgrid2 {
             grid,
@@ -174,19 +173,12 @@ pub struct Meshgrid3 {
 }
 
 impl Meshgrid3 {
-    pub fn new(xs: &Vec<f64>, ys: &Vec<f64>, zs: &Vec<f64>) -> Meshgrid3 {
+    pub fn new(xs: &[f64], ys: &[f64], zs: &[f64]) -> Meshgrid3 {
         let grid: Vec<(f64, f64, f64)> = zs
-            .clone()
             .iter()
             .flat_map(move |z| {
-                ys.clone()
-                    .iter()
-                    .flat_map(move |y| {
-                        xs.clone()
-                            .iter()
-                            .map(move |x| (*x, *y, *z))
-                            .collect::<Vec<_>>()
-                    })
+                ys.iter()
+                    .flat_map(move |y| xs.iter().map(move |x| (*x, *y, *z)).collect::<Vec<_>>())
                     .collect::<Vec<_>>()
             })
             .collect();
@@ -369,7 +361,7 @@ mod tests {
 
     #[test]
     fn test() {
-        let m = Meshgrid2::new(&vec![1., 2.], &vec![3., 4.]);
+        let m = Meshgrid2::new(&[1., 2.], &[3., 4.]);
         println!("{:?}", m);
         println!("{:?}", m.x());
         println!("{:?}", m.y());
@@ -0,0 +1,340 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind, Result},
+    mem::size_of,
+    path::Path,
+};
+
+use ndarray::{ArrayBase, ArrayD, Data, Dimension, IxDyn, ShapeBuilder};
+
+use crate::particles::Particles;
+
+const MAGIC: &[u8; 6] = b"\x93NUMPY";
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+/// Element types with a NumPy dtype, stored little-endian.
+pub trait NpyElement: Copy {
+    const DESCR: &'static str;
+    fn put(self, out: &mut Vec<u8>);
+    fn get(bytes: &[u8]) -> Self;
+}
+
+macro_rules! npy_element {
+    ($t:ty, $descr:expr) => {
+        impl NpyElement for $t {
+            const DESCR: &'static str = $descr;
+
+            fn put(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn get(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+npy_element!(f64, "<f8");
+npy_element!(f32, "<f4");
+npy_element!(i64, "<i8");
+npy_element!(u64, "<u8");
+npy_element!(i32, "<i4");
+npy_element!(u32, "<u4");
+npy_element!(u16, "<u2");
+npy_element!(u8, "|u1");
+
+/// Version 1.0 `.npy` encoding of `array`, in C order whatever its memory layout.
+pub fn to_npy<A, S, D>(array: &ArrayBase<S, D>) -> Vec<u8>
+where
+    A: NpyElement,
+    S: Data<Elem = A>,
+    D: Dimension,
+{
+    let shape = match array.shape() {
+        [n] => format!("({},)", n),
+        shape => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        A::DESCR,
+        shape
+    );
+    // magic, version and length take 10 bytes; the data starts 64-byte aligned
+    let padded = (10 + header.len() + 1).div_ceil(64) * 64;
+    let header = format!("{:<1$}\n", header, padded - 11);
+
+    let mut out = Vec::with_capacity(padded + array.len() * size_of::<A>());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&[1, 0]);
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    array.iter().for_each(|x| x.put(&mut out));
+    out
+}
+
+/// Array from `.npy` bytes of versions 1 to 3, in C or Fortran order. The dtype must
+/// match `A` exactly.
+pub fn from_npy<A: NpyElement>(bytes: &[u8]) -> Result<ArrayD<A>> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(invalid("not an .npy file"));
+    }
+    let (len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        version => return Err(invalid(&format!("unsupported .npy version {}", version))),
+    };
+    let header = bytes
+        .get(start..start + len)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or_else(|| invalid("truncated .npy header"))?;
+
+    let value = |key: &str| -> Result<&str> {
+        let key = format!("'{}':", key);
+        let at = header
+            .find(&key)
+            .ok_or_else(|| invalid(&format!("no {} in .npy header", key)))?;
+        Ok(header[at + key.len()..].trim_start())
+    };
+    let descr = value("descr")?
+        .split('\'')
+        .nth(1)
+        .ok_or_else(|| invalid("malformed descr"))?;
+    // single bytes have no byte order, and either marker is fine
+    if descr != A::DESCR && !(size_of::<A>() == 1 && descr[1..] == A::DESCR[1..]) {
+        return Err(invalid(&format!(
+            "expected dtype {}, found {}",
+            A::DESCR,
+            descr
+        )));
+    }
+    let fortran_order = value("fortran_order")?.starts_with("True");
+    let shape = value("shape")?;
+    let shape: Vec<usize> = shape[1..shape.find(')').ok_or_else(|| invalid("malformed shape"))?]
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.parse().map_err(|_| invalid("malformed shape")))
+        .collect::<Result<_>>()?;
+
+    let data = &bytes[start + len..];
+    let n: usize = shape.iter().product();
+    if data.len() != n * size_of::<A>() {
+        return Err(invalid("data does not match the .npy shape"));
+    }
+    let data: Vec<A> = data.chunks(size_of::<A>()).map(A::get).collect();
+    let array = if fortran_order {
+        ArrayD::from_shape_vec(IxDyn(&shape).f(), data)
+    } else {
+        ArrayD::from_shape_vec(IxDyn(&shape), data)
+    };
+    Ok(array.unwrap())
+}
+
+pub fn write_npy<A, S, D>(path: impl AsRef<Path>, array: &ArrayBase<S, D>) -> Result<()>
+where
+    A: NpyElement,
+    S: Data<Elem = A>,
+    D: Dimension,
+{
+    fs::write(path, to_npy(array))
+}
+
+pub fn read_npy<A: NpyElement>(path: impl AsRef<Path>) -> Result<ArrayD<A>> {
+    from_npy(&fs::read(path)?)
+}
+
+/// Bundles `.npy` encoded arrays into an uncompressed `.npz`, as `numpy.savez` does;
+/// `numpy.load` gives them back under their names.
+pub fn write_npz(path: impl AsRef<Path>, arrays: &[(&str, Vec<u8>)]) -> Result<()> {
+    let mut out = vec![];
+    let mut directory = vec![];
+    for (name, data) in arrays {
+        let name = format!("{}.npy", name);
+        let offset = u32::try_from(out.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let crc = crc32fast::hash(data);
+        // version 2.0, no flags, stored, 1980-01-01 00:00
+        let fields = |out: &mut Vec<u8>| {
+            [20u16, 0, 0, 0, 0x21].iter().for_each(|x| put(out, x));
+            [crc, size, size].iter().for_each(|x| put(out, x));
+            put(out, &(name.len() as u16));
+            put(out, &0u16);
+        };
+
+        put(&mut out, &LOCAL_HEADER);
+        fields(&mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        put(&mut directory, &CENTRAL_HEADER);
+        put(&mut directory, &20u16);
+        fields(&mut directory);
+        [0u16, 0, 0].iter().for_each(|x| put(&mut directory, x));
+        [0u32, offset].iter().for_each(|x| put(&mut directory, x));
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let start = u32::try_from(out.len()).map_err(|_| too_large())?;
+    out.extend_from_slice(&directory);
+    put(&mut out, &END_OF_CENTRAL_DIRECTORY);
+    let entries = arrays.len() as u16;
+    [0u16, 0, entries, entries]
+        .iter()
+        .for_each(|x| put(&mut out, x));
+    [directory.len() as u32, start]
+        .iter()
+        .for_each(|x| put(&mut out, x));
+    put(&mut out, &0u16);
+    fs::write(path, out)
+}
+
+/// `.npy` bytes of every array in an uncompressed `.npz`, by name, for `from_npy`.
+/// Reads both our own and `numpy.savez` archives, whose entries carry zip64 sizes.
+pub fn read_npz(path: impl AsRef<Path>) -> Result<BTreeMap<String, Vec<u8>>> {
+    let bytes = fs::read(path)?;
+    let mut arrays = BTreeMap::new();
+    let mut at = 0;
+    while at + 30 <= bytes.len() && u32_at(&bytes, at) == LOCAL_HEADER {
+        let flags = u16_at(&bytes, at + 6);
+        if flags & 0x8 != 0 || u16_at(&bytes, at + 8) != 0 {
+            return Err(invalid(
+                "only uncompressed .npz archives (numpy.savez) are supported",
+            ));
+        }
+        let mut size = u32_at(&bytes, at + 18) as usize;
+        let name_len = u16_at(&bytes, at + 26) as usize;
+        let extra_len = u16_at(&bytes, at + 28) as usize;
+        let name = &bytes[at + 30..at + 30 + name_len];
+        let mut extra = &bytes[at + 30 + name_len..at + 30 + name_len + extra_len];
+        while extra.len() >= 4 {
+            let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+            if id == 1 && size == u32::MAX as usize {
+                // zip64: uncompressed then compressed size, equal when stored
+                size = u64::from_le_bytes(extra[4..12].try_into().unwrap()) as usize;
+            }
+            extra = &extra[(4 + len).min(extra.len())..];
+        }
+        let start = at + 30 + name_len + extra_len;
+        let data = bytes
+            .get(start..start + size)
+            .ok_or_else(|| invalid("truncated .npz entry"))?;
+        let name = String::from_utf8_lossy(name);
+        let name = name.strip_suffix(".npy").unwrap_or(&name).to_string();
+        arrays.insert(name, data.to_vec());
+        at = start + size;
+    }
+    Ok(arrays)
+}
+
+/// `.npy` encodings of the particle arrays, with one row per particle as Python
+/// expects, and the `ParticleType` codes as bytes. Positions and velocities have
+/// columns x, y, z, whereas mesh grids saved alongside with `to_npy` keep the
+/// [z, y, x] indexing of the force mesh, so `density[k, j, i]` is the cell at x = i.
+pub fn particle_arrays(particles: &Particles) -> Vec<(&'static str, Vec<u8>)> {
+    let types = particles.types.iter().map(|t| t.code());
+    vec![
+        ("ids", to_npy(&particles.ids)),
+        ("positions", to_npy(&particles.positions.t())),
+        ("velocities", to_npy(&particles.velocities.t())),
+        ("masses", to_npy(&particles.masses)),
+        ("types", to_npy(&ndarray::Array1::from_iter(types))),
+    ]
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn too_large() -> Error {
+    Error::new(ErrorKind::InvalidInput, ".npz entries are limited to 4 GiB")
+}
+
+fn put<T: NpyElement>(out: &mut Vec<u8>, x: &T) {
+    x.put(out)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particles::ParticleType;
+    use ndarray::{array, Array3, Ix2, Ix3};
+
+    #[test]
+    fn npy_round_trip() {
+        let grid = Array3::from_shape_fn((2, 3, 4), |(i, j, k)| (100 * i + 10 * j + k) as f64);
+        let bytes = to_npy(&grid);
+        assert_eq!(bytes[8] as usize + 10, 128);
+        assert!(std::str::from_utf8(&bytes[10..128])
+            .unwrap()
+            .starts_with("{'descr': '<f8', 'fortran_order': False, 'shape': (2, 3, 4), }"));
+        let read = from_npy::<f64>(&bytes).unwrap();
+        assert_eq!(read.into_dimensionality::<Ix3>().unwrap(), grid);
+        assert!(from_npy::<f32>(&bytes).is_err());
+
+        // transposed views are written in logical order
+        let x = array![[1f32, 2., 3.], [4., 5., 6.]];
+        let read = from_npy::<f32>(&to_npy(&x.t())).unwrap();
+        assert_eq!(read.into_dimensionality::<Ix2>().unwrap(), x.t());
+
+        let mut fortran = to_npy(&array![1u8, 2, 3, 4]);
+        let header = std::str::from_utf8(&fortran[10..])
+            .unwrap()
+            .replace("False, 'shape': (4,)", "True, 'shape': (2, 2)")
+            .replacen(" \n", "\n", 1);
+        fortran.truncate(10);
+        fortran.extend_from_slice(header.as_bytes());
+        let read = from_npy::<u8>(&fortran).unwrap();
+        assert_eq!(read, array![[1u8, 3], [2, 4]].into_dyn());
+    }
+
+    #[test]
+    fn npz_round_trip() {
+        let particles = Particles::new(
+            array![[0.5, 1.5], [2., 3.], [4., 5.]],
+            array![[0.1, 0.2], [0., 0.], [1., 2.]],
+            1.,
+            ParticleType::Baryon,
+        );
+        let mut arrays = particle_arrays(&particles);
+        arrays.push(("density", to_npy(&Array3::<f32>::ones((2, 2, 2)))));
+        let path = std::env::temp_dir().join("nbody_test_arrays.npz");
+        write_npz(&path, &arrays).unwrap();
+        let read = read_npz(&path).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(read.len(), 6);
+        let positions = from_npy::<f64>(&read["positions"]).unwrap();
+        assert_eq!(positions, particles.positions.t().into_dyn());
+        assert_eq!(
+            from_npy::<u64>(&read["ids"]).unwrap(),
+            array![0u64, 1].into_dyn()
+        );
+        assert_eq!(
+            from_npy::<u8>(&read["types"]).unwrap(),
+            array![1u8, 1].into_dyn()
+        );
+        assert_eq!(from_npy::<f32>(&read["density"]).unwrap().sum(), 8.);
+    }
+}
@@ -4,38 +4,53 @@ pub fn test() -> String {
 
 #[cfg(test)]
 mod tests {
-    use ndarray::{s, Array2, Array3};
+    use ndarray::{s, Array3};
 
-    use crate::config::{A_INIT, N_CELLS, N_PARTICLES};
+    use crate::config::{N_CELLS, N_PARTICLES};
     use crate::density::density;
     use crate::fourier::ksq_inv;
     use crate::integrate::update;
     use crate::utils::{array_2_to_image, hist};
-    use crate::{ic::initial_conditions, random_field::gaussian_random_field};
+    use crate::{
+        ic::{initial_conditions, LptOrder, ParticleLoad},
+        particles::Particles,
+        power_spectrum::PowerSpectrum,
+        random_field::{gaussian_random_field, RandomFieldOptions},
+    };
 
     #[test]
     fn simulate() {
         let average_density = (N_CELLS / N_PARTICLES).pow(3) as f64;
-        let rho: Array3<f64> = gaussian_random_field(0.845, 3.685);
-        let (mut positions, mut velocities): (Array2<f64>, Array2<f64>) =
-            initial_conditions(rho.clone());
+        let rho: Array3<f64> = gaussian_random_field(
+            &PowerSpectrum::power_law(-0.845, 3.685),
+            &RandomFieldOptions::default(),
+        );
+        let Particles {
+            mut positions,
+            mut velocities,
+            ..
+        } = initial_conditions(rho.clone(), LptOrder::Second, ParticleLoad::Lattice);
 
         let img = array_2_to_image(positions.clone(), N_CELLS);
-        img.save("./img/positions0.png");
+        let _ = img.save("./img/positions0.png");
         let fgrid = ksq_inv();
         for t in (1..=300).step_by(10) {
             let den = density(&positions, average_density);
             (positions, velocities) = update(den, positions, velocities, &fgrid, t as f64, 10.);
             let img = array_2_to_image(positions.clone(), N_CELLS);
-            img.save(format!("./img/positions{}.png", t));
+            let _ = img.save(format!("./img/positions{}.png", t));
         }
     }
 
     #[test]
     fn plot_dist() {
-        let average_density = (N_CELLS / N_PARTICLES).pow(3) as f64;
-        let rho: Array3<f64> = gaussian_random_field(0.845, 3.685);
-        let (positions, velocities): (Array2<f64>, Array2<f64>) = initial_conditions(rho.clone());
+        let _average_density = (N_CELLS / N_PARTICLES).pow(3) as f64;
+        let rho: Array3<f64> = gaussian_random_field(
+            &PowerSpectrum::power_law(-0.845, 3.685),
+            &RandomFieldOptions::default(),
+        );
+        let positions =
+            initial_conditions(rho.clone(), LptOrder::Second, ParticleLoad::Lattice).positions;
         ['x', 'y', 'z'].iter().enumerate().for_each(|(dir, c)| {
             let a = positions
                 .slice(s![dir, ..])
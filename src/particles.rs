@@ -0,0 +1,223 @@
+use ndarray::{concatenate, Array1, Array2, Array3, Axis};
+
+use crate::{curve::SpaceFillingCurve, density::weighted_density, float::Float};
+
+/// Role of a particle in the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParticleType {
+    DarkMatter,
+    Baryon,
+    /// Massive neutrinos with thermal velocities
+    Neutrino,
+    /// Coarse, heavier particles surrounding a zoom region
+    Boundary,
+}
+
+impl ParticleType {
+    /// One-byte code used in binary outputs.
+    pub fn code(self) -> u8 {
+        match self {
+            ParticleType::DarkMatter => 0,
+            ParticleType::Baryon => 1,
+            ParticleType::Neutrino => 2,
+            ParticleType::Boundary => 3,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<ParticleType> {
+        match code {
+            0 => Some(ParticleType::DarkMatter),
+            1 => Some(ParticleType::Baryon),
+            2 => Some(ParticleType::Neutrino),
+            3 => Some(ParticleType::Boundary),
+            _ => None,
+        }
+    }
+}
+
+/// Particle store, one column of `positions` and `velocities` per particle, in cell
+/// units, with a mass, type and stable ID each. Initial conditions and outputs are in
+/// `f64`; the main loop may step a `cast` copy in the storage precision of PRECISION.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Particles<F = f64> {
+    /// Lagrangian lattice index, offset per species, kept through every reordering
+    pub ids: Array1<u64>,
+    pub positions: Array2<F>,
+    pub velocities: Array2<F>,
+    pub masses: Array1<F>,
+    pub types: Vec<ParticleType>,
+}
+
+impl<F: Float> Particles<F> {
+    /// Equal-mass particles of a single type, with IDs following the column order,
+    /// i.e. the lattice index for lattice loads.
+    pub fn new(
+        positions: Array2<F>,
+        velocities: Array2<F>,
+        mass: F,
+        kind: ParticleType,
+    ) -> Particles<F> {
+        let n = positions.len_of(Axis(1));
+        Particles {
+            ids: Array1::from_iter(0..n as u64),
+            positions,
+            velocities,
+            masses: Array1::from_elem(n, mass),
+            types: vec![kind; n],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.masses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masses.is_empty()
+    }
+
+    /// The same particles stored in `G`, exact when widening.
+    pub fn cast<G: Float>(&self) -> Particles<G> {
+        let cast = |x: &F| G::of(x.f64());
+        Particles {
+            ids: self.ids.clone(),
+            positions: self.positions.map(cast),
+            velocities: self.velocities.map(cast),
+            masses: self.masses.map(cast),
+            types: self.types.clone(),
+        }
+    }
+
+    /// Adds another species, shifting its IDs past the current ones.
+    pub fn append(&mut self, other: Particles<F>) {
+        let offset = self.ids.iter().max().map_or(0, |id| id + 1);
+        self.ids = concatenate![Axis(0), self.ids, other.ids.map(|id| id + offset)];
+        self.positions = concatenate![Axis(1), self.positions, other.positions];
+        self.velocities = concatenate![Axis(1), self.velocities, other.velocities];
+        self.masses = concatenate![Axis(0), self.masses, other.masses];
+        self.types.extend(other.types);
+    }
+
+    /// Copy of the particles of one type.
+    pub fn of_type(&self, kind: ParticleType) -> Particles<F> {
+        let indices: Vec<usize> = (0..self.len()).filter(|p| self.types[*p] == kind).collect();
+        Particles {
+            ids: self.ids.select(Axis(0), &indices),
+            positions: self.positions.select(Axis(1), &indices),
+            velocities: self.velocities.select(Axis(1), &indices),
+            masses: self.masses.select(Axis(0), &indices),
+            types: vec![kind; indices.len()],
+        }
+    }
+
+    /// Reorders every particle so that the new p-th particle is the old `order[p]`.
+    pub fn permute(&mut self, order: &[usize]) {
+        self.ids = self.ids.select(Axis(0), order);
+        self.positions = self.positions.select(Axis(1), order);
+        self.velocities = self.velocities.select(Axis(1), order);
+        self.masses = self.masses.select(Axis(0), order);
+        self.types = order.iter().map(|p| self.types[*p]).collect();
+    }
+
+    /// Stable sort of the particles by the `curve` key of their cell, so that particles
+    /// close in space are close in memory for the deposit and force gather.
+    pub fn sort_along(&mut self, curve: SpaceFillingCurve) {
+        let keys: Vec<u64> = self
+            .positions
+            .columns()
+            .into_iter()
+            .map(|x| curve.key([x[0], x[1], x[2]].map(F::f64)))
+            .collect();
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.sort_by_key(|p| keys[*p]);
+        self.permute(&order);
+    }
+
+    /// Column of the particle with the given ID.
+    pub fn index_of(&self, id: u64) -> Option<usize> {
+        self.ids.iter().position(|x| *x == id)
+    }
+
+    pub fn total_mass(&self) -> f64 {
+        self.masses.iter().map(|m| m.f64()).sum()
+    }
+
+    pub fn mass_of(&self, kind: ParticleType) -> f64 {
+        self.masses
+            .iter()
+            .zip(&self.types)
+            .filter(|(_, t)| **t == kind)
+            .map(|(m, _)| m.f64())
+            .sum()
+    }
+
+    /// Mass-weighted mean velocity.
+    pub fn bulk_velocity(&self) -> Array1<f64> {
+        self.velocities.mapv(F::f64).dot(&self.masses.mapv(F::f64)) / self.total_mass()
+    }
+
+    /// CIC density of all particles on the force mesh.
+    pub fn density(&self) -> Array3<F> {
+        weighted_density(&self.positions, &self.masses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::N_CELLS;
+    use ndarray::array;
+
+    #[test]
+    fn masses_follow_types() {
+        let mut particles: Particles = Particles::new(
+            array![[1., 2.], [1., 2.], [1., 2.]],
+            array![[1., -1.], [0., 0.], [0., 0.]],
+            1.,
+            ParticleType::DarkMatter,
+        );
+        particles.append(Particles::new(
+            array![[3.], [3.], [3.]],
+            array![[4.], [0.], [0.]],
+            2.,
+            ParticleType::Baryon,
+        ));
+        assert_eq!(particles.len(), 3);
+        assert_eq!(particles.mass_of(ParticleType::Baryon), 2.);
+        assert_eq!(particles.of_type(ParticleType::Baryon).masses, array![2.]);
+        assert_eq!(particles.bulk_velocity(), array![2., 0., 0.]);
+        assert_eq!(particles.ids, array![0, 1, 2]);
+
+        particles.permute(&[2, 0, 1]);
+        let p = particles.index_of(2).unwrap();
+        assert_eq!(p, 0);
+        assert_eq!(particles.types[p], ParticleType::Baryon);
+        assert_eq!(particles.positions[[0, p]], 3.);
+        assert_eq!(
+            particles.velocities[[0, particles.index_of(1).unwrap()]],
+            -1.
+        );
+
+        let density = particles.density();
+        let before = particles.clone();
+        particles.sort_along(SpaceFillingCurve::Hilbert);
+        (particles.density() - &density)
+            .iter()
+            .for_each(|d| assert!(d.abs() < 1e-12));
+        let keys: Vec<u64> = particles
+            .positions
+            .columns()
+            .into_iter()
+            .map(|x| SpaceFillingCurve::Hilbert.key([x[0], x[1], x[2]]))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+        for (p, id) in particles.ids.iter().enumerate() {
+            let q = before.index_of(*id).unwrap();
+            assert_eq!(particles.positions.column(p), before.positions.column(q));
+            assert_eq!(particles.velocities.column(p), before.velocities.column(q));
+            assert_eq!(particles.masses[p], before.masses[q]);
+            assert_eq!(particles.types[p], before.types[q]);
+        }
+        assert!((density.sum() - particles.total_mass()).abs() < 1e-12);
+        assert_eq!(density.dim(), (N_CELLS, N_CELLS, N_CELLS));
+    }
+}
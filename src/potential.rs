@@ -1,14 +1,14 @@
 use ndarray::Array3;
-use rustfft::num_complex::{Complex, Complex64};
+use rustfft::num_complex::Complex;
 
-use crate::config::{N_CELLS, OMEGA_M0};
+use crate::config::OMEGA_M0;
+use crate::float::Float;
 use crate::fourier::*;
-use crate::utils::array_3_to_image;
 
-pub fn potential(density: Array3<f64>, fgrid: &Array3<f64>, t: f64) -> Array3<f64> {
-    let grid: Array3<Complex64> = density_k(density);
-    let grid: Array3<Complex64> = potential_k(grid, fgrid, t);
-    let res: Array3<f64> = potential_real(grid);
+pub fn potential<F: Float>(density: Array3<F>, fgrid: &Array3<F>, t: f64) -> Array3<F> {
+    let grid: Array3<Complex<F>> = density_k(density);
+    let grid: Array3<Complex<F>> = potential_k(grid, fgrid, t);
+    let res: Array3<F> = potential_real(grid);
     // let img = array_3_to_image(
     //     res.map(|x| ((*x) * 100.).min(u8::MAX as f64 - 1.) as u8),
     //     Some(N_CELLS),
@@ -17,17 +17,27 @@ pub fn potential(density: Array3<f64>, fgrid: &Array3<f64>, t: f64) -> Array3<f6
     res
 }
 
-fn density_k(density: Array3<f64>) -> Array3<Complex64> {
-    forward(&density.map(|x| Complex { re: *x, im: 0. }))
+fn density_k<F: Float>(density: Array3<F>) -> Array3<Complex<F>> {
+    forward(&density.map(|x| Complex {
+        re: *x,
+        im: F::zero(),
+    }))
 }
 
-pub fn potential_k(density: Array3<Complex64>, fgrid: &Array3<f64>, t: f64) -> Array3<Complex64> {
-    let fgrid: Array3<Complex64> = fgrid.map(|x| Complex { re: *x, im: 0. });
-    let c = Complex::new((-3. * OMEGA_M0 / 8.0) / t, 0.);
-    let a: Array3<Complex<f64>> = density * fgrid;
+pub fn potential_k<F: Float>(
+    density: Array3<Complex<F>>,
+    fgrid: &Array3<F>,
+    t: f64,
+) -> Array3<Complex<F>> {
+    let fgrid: Array3<Complex<F>> = fgrid.map(|x| Complex {
+        re: *x,
+        im: F::zero(),
+    });
+    let c = Complex::new(F::of((-3. * OMEGA_M0 / 8.0) / t), F::zero());
+    let a: Array3<Complex<F>> = density * fgrid;
     (a).map(|x| c * x)
 }
 
-fn potential_real(potential_k: Array3<Complex64>) -> Array3<f64> {
+fn potential_real<F: Float>(potential_k: Array3<Complex<F>>) -> Array3<F> {
     inverse(&potential_k).map(|x| x.re)
 }
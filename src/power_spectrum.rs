@@ -0,0 +1,425 @@
+use std::{
+    f64::consts::{E, PI},
+    sync::OnceLock,
+};
+
+use crate::{
+    config::{BOX_SIZE, H0, N_S, OMEGA_B0, OMEGA_M0, SIGMA_8, T_CMB},
+    cosmology::RHO_CRIT,
+    tabulated::Table,
+};
+
+/// Linear matter transfer function, normalized to unity on large scales.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transfer {
+    /// T(k) = 1, i.e. a pure power law P(k) = A k^n.
+    None,
+    /// Eisenstein & Hu (1998) fit including baryon acoustic oscillations.
+    EisensteinHu,
+    /// Eisenstein & Hu (1998) zero-baryon-like fit without wiggles.
+    EisensteinHuNoWiggle,
+    /// CDM component of the Eisenstein & Hu (1998) fit.
+    EisensteinHuCdm,
+    /// Baryon component of the Eisenstein & Hu (1998) fit.
+    EisensteinHuBaryon,
+    /// Bardeen, Bond, Kaiser & Szalay (1986) with Sugiyama (1995) shape parameter.
+    Bbks,
+    /// Interpolated table, e.g. from CAMB or CLASS output.
+    Table(Table),
+    /// Square root of a tabulated P(k) standing in for the transfer function, see
+    /// `PowerSpectrum::from_power_table`.
+    PowerTable(Table),
+    /// Warm dark matter cut-off of Viel et al. (2005) applied to another transfer
+    /// function, for a thermal relic of the given mass in keV.
+    Wdm(f64, Box<Transfer>),
+}
+
+impl Transfer {
+    pub fn eval(&self, k: f64) -> f64 {
+        match self {
+            Transfer::None => 1.,
+            Transfer::EisensteinHu => eisenstein_hu(k),
+            Transfer::EisensteinHuNoWiggle => eisenstein_hu_no_wiggle(k),
+            Transfer::EisensteinHuCdm => eisenstein_hu_cdm(k),
+            Transfer::EisensteinHuBaryon => eisenstein_hu_baryon(k),
+            Transfer::Bbks => bbks(k),
+            Transfer::Table(table) | Transfer::PowerTable(table) => table.eval(k),
+            Transfer::Wdm(mass, cdm) => wdm_suppression(k, *mass) * cdm.eval(k),
+        }
+    }
+
+    /// Whether this relates the primordial potential to the density, as opposed to a
+    /// tabulated P(k) that folds in the unknown primordial amplitude and tilt.
+    pub fn is_transfer_function(&self) -> bool {
+        match self {
+            Transfer::PowerTable(_) => false,
+            Transfer::Wdm(_, cdm) => cdm.is_transfer_function(),
+            _ => true,
+        }
+    }
+}
+
+/// P(k) = amplitude * k^n_s * T(k)^2 at a = 1, with k in h/Mpc and P in (Mpc/h)^3.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerSpectrum {
+    pub transfer: Transfer,
+    pub n_s: f64,
+    pub amplitude: f64,
+}
+
+impl PowerSpectrum {
+    /// Spectrum with the configured tilt, normalized to SIGMA_8.
+    pub fn new(transfer: Transfer) -> PowerSpectrum {
+        PowerSpectrum {
+            transfer,
+            n_s: N_S,
+            amplitude: 1.,
+        }
+        .with_sigma8(SIGMA_8)
+    }
+
+    pub fn power_law(n: f64, amplitude: f64) -> PowerSpectrum {
+        PowerSpectrum {
+            transfer: Transfer::None,
+            n_s: n,
+            amplitude,
+        }
+    }
+
+    /// Uses a tabulated P(k) as is, stored as the transfer function sqrt(P) with
+    /// n_s = 0 and unit amplitude. It keeps the table's own normalization, SIGMA_8
+    /// is not imposed.
+    pub fn from_power_table(table: Table) -> PowerSpectrum {
+        PowerSpectrum {
+            transfer: Transfer::PowerTable(table.map(f64::sqrt)),
+            n_s: 0.,
+            amplitude: 1.,
+        }
+    }
+
+    /// Same amplitude and tilt with another transfer function, e.g. the spectrum of a
+    /// single species normalized against the total matter sigma_8.
+    pub fn with_transfer(&self, transfer: Transfer) -> PowerSpectrum {
+        PowerSpectrum {
+            transfer,
+            ..self.clone()
+        }
+    }
+
+    /// Truncates the spectrum for a warm dark matter particle of `mass` keV, keeping
+    /// the CDM normalization.
+    pub fn with_wdm(&self, mass: f64) -> PowerSpectrum {
+        self.with_transfer(Transfer::Wdm(mass, Box::new(self.transfer.clone())))
+    }
+
+    pub fn transfer(&self, k: f64) -> f64 {
+        self.transfer.eval(k)
+    }
+
+    pub fn power(&self, k: f64) -> f64 {
+        if k <= 0. {
+            return 0.;
+        }
+        self.amplitude * k.powf(self.n_s) * self.transfer(k).powi(2)
+    }
+
+    /// RMS of the linear density field smoothed with a top-hat of radius r in Mpc/h.
+    pub fn sigma(&self, r: f64) -> f64 {
+        // midpoint rule in ln k; the window kills everything past k ~ 100 / r
+        let (ln_k_min, ln_k_max) = ((1e-5f64).ln(), (1e3 / r).ln());
+        let n = 4096;
+        let d_ln_k = (ln_k_max - ln_k_min) / n as f64;
+        let variance: f64 = (0..n)
+            .map(|i| {
+                let k = (ln_k_min + (i as f64 + 0.5) * d_ln_k).exp();
+                k.powi(3) * self.power(k) * top_hat(k * r).powi(2)
+            })
+            .sum::<f64>()
+            * d_ln_k
+            / (2. * PI.powi(2));
+        variance.sqrt()
+    }
+
+    /// sigma(M) for a top-hat enclosing mass m in M_sun/h at the mean matter density.
+    pub fn sigma_mass(&self, m: f64) -> f64 {
+        self.sigma((3. * m / (4. * PI * OMEGA_M0 * RHO_CRIT)).cbrt())
+    }
+
+    /// Rescales the amplitude so that sigma(8 Mpc/h) = sigma8 at a = 1.
+    pub fn with_sigma8(self, sigma8: f64) -> PowerSpectrum {
+        let current = self.sigma(8.);
+        PowerSpectrum {
+            amplitude: self.amplitude * (sigma8 / current).powi(2),
+            ..self
+        }
+    }
+}
+
+/// Fourier transform of a real-space spherical top-hat window.
+pub fn top_hat(x: f64) -> f64 {
+    if x < 1e-4 {
+        return 1.;
+    }
+    3. * (x.sin() - x * x.cos()) / x.powi(3)
+}
+
+/// Fundamental mode of the simulation box in h/Mpc.
+pub fn fundamental_mode() -> f64 {
+    2. * PI / BOX_SIZE as f64
+}
+
+fn theta_cmb() -> f64 {
+    T_CMB / 2.7
+}
+
+// Eisenstein & Hu (1998), ApJ 496, 605. Equation numbers refer to that paper.
+struct EisensteinHu {
+    omh2: f64,
+    obh2: f64,
+    f_b: f64,
+    f_c: f64,
+    k_eq: f64,
+    sound_horizon: f64,
+    k_silk: f64,
+    alpha_c: f64,
+    beta_c: f64,
+    alpha_b: f64,
+    beta_b: f64,
+    beta_node: f64,
+}
+
+impl EisensteinHu {
+    fn new() -> EisensteinHu {
+        let theta = theta_cmb();
+        let omh2 = OMEGA_M0 * H0.powi(2);
+        let obh2 = OMEGA_B0 * H0.powi(2);
+        let f_b = OMEGA_B0 / OMEGA_M0;
+        let f_c = 1. - f_b;
+
+        // eq. 2-4
+        let z_eq = 2.50e4 * omh2 * theta.powi(-4);
+        let k_eq = 7.46e-2 * omh2 * theta.powi(-2);
+        let b1 = 0.313 * omh2.powf(-0.419) * (1. + 0.607 * omh2.powf(0.674));
+        let b2 = 0.238 * omh2.powf(0.223);
+        let z_d =
+            1291. * omh2.powf(0.251) / (1. + 0.659 * omh2.powf(0.828)) * (1. + b1 * obh2.powf(b2));
+
+        // eq. 5-7
+        let r = |z: f64| 31.5 * obh2 * theta.powi(-4) * (z / 1e3).recip();
+        let (r_eq, r_d) = (r(z_eq), r(z_d));
+        let sound_horizon = 2. / (3. * k_eq)
+            * (6. / r_eq).sqrt()
+            * (((1. + r_d).sqrt() + (r_d + r_eq).sqrt()) / (1. + r_eq.sqrt())).ln();
+        let k_silk = 1.6 * obh2.powf(0.52) * omh2.powf(0.73) * (1. + (10.4 * omh2).powf(-0.95));
+
+        // eq. 11-12
+        let a1 = (46.9 * omh2).powf(0.670) * (1. + (32.1 * omh2).powf(-0.532));
+        let a2 = (12.0 * omh2).powf(0.424) * (1. + (45.0 * omh2).powf(-0.582));
+        let alpha_c = a1.powf(-f_b) * a2.powf(-f_b.powi(3));
+        let bb1 = 0.944 / (1. + (458. * omh2).powf(-0.708));
+        let bb2 = (0.395 * omh2).powf(-0.0266);
+        let beta_c = 1. / (1. + bb1 * (f_c.powf(bb2) - 1.));
+
+        // eq. 14-15, 22-24
+        let y = (1. + z_eq) / (1. + z_d);
+        let g = y
+            * (-6. * (1. + y).sqrt()
+                + (2. + 3. * y) * (((1. + y).sqrt() + 1.) / ((1. + y).sqrt() - 1.)).ln());
+        let alpha_b = 2.07 * k_eq * sound_horizon * (1. + r_d).powf(-0.75) * g;
+        let beta_b = 0.5 + f_b + (3. - 2. * f_b) * ((17.2 * omh2).powi(2) + 1.).sqrt();
+        let beta_node = 8.41 * omh2.powf(0.435);
+
+        EisensteinHu {
+            omh2,
+            obh2,
+            f_b,
+            f_c,
+            k_eq,
+            sound_horizon,
+            k_silk,
+            alpha_c,
+            beta_c,
+            alpha_b,
+            beta_b,
+            beta_node,
+        }
+    }
+
+    // eq. 19-20, k in 1/Mpc
+    fn t0(&self, k: f64, alpha: f64, beta: f64) -> f64 {
+        let q = k / (13.41 * self.k_eq);
+        let l = (E + 1.8 * beta * q).ln();
+        let c = 14.2 / alpha + 386. / (1. + 69.9 * q.powf(1.08));
+        l / (l + c * q.powi(2))
+    }
+
+    fn cdm(&self, k: f64) -> f64 {
+        // eq. 17-18
+        let f = 1. / (1. + (k * self.sound_horizon / 5.4).powi(4));
+        f * self.t0(k, 1., self.beta_c) + (1. - f) * self.t0(k, self.alpha_c, self.beta_c)
+    }
+
+    fn baryon(&self, k: f64) -> f64 {
+        // eq. 21-22
+        let ks = k * self.sound_horizon;
+        let s_tilde = self.sound_horizon / (1. + (self.beta_node / ks).powi(3)).cbrt();
+        let ks_tilde = k * s_tilde;
+        let j0 = if ks_tilde.abs() < 1e-8 {
+            1.
+        } else {
+            ks_tilde.sin() / ks_tilde
+        };
+        (self.t0(k, 1., 1.) / (1. + (ks / 5.2).powi(2))
+            + self.alpha_b / (1. + (self.beta_b / ks).powi(3))
+                * (-(k / self.k_silk).powf(1.4)).exp())
+            * j0
+    }
+
+    // eq. 26-31, k in 1/Mpc
+    fn no_wiggle(&self, k: f64) -> f64 {
+        let theta = theta_cmb();
+        let alpha_gamma = 1. - 0.328 * (431. * self.omh2).ln() * self.f_b
+            + 0.38 * (22.3 * self.omh2).ln() * self.f_b.powi(2);
+        let s = 44.5 * (9.83 / self.omh2).ln() / (1. + 10. * self.obh2.powf(0.75)).sqrt();
+        let gamma_eff =
+            OMEGA_M0 * H0 * (alpha_gamma + (1. - alpha_gamma) / (1. + (0.43 * k * s).powi(4)));
+        let q = k / H0 * theta.powi(2) / gamma_eff;
+        let l0 = (2. * E + 1.8 * q).ln();
+        let c0 = 14.2 + 731. / (1. + 62.5 * q);
+        l0 / (l0 + c0 * q.powi(2))
+    }
+}
+
+// the fit depends only on the configured cosmology, so it is set up on first use
+fn fit() -> &'static EisensteinHu {
+    static FIT: OnceLock<EisensteinHu> = OnceLock::new();
+    FIT.get_or_init(EisensteinHu::new)
+}
+
+/// Eisenstein & Hu (1998) transfer function with BAO, k in h/Mpc.
+pub fn eisenstein_hu(k: f64) -> f64 {
+    let eh = fit();
+    let k = k * H0;
+    eh.f_b * eh.baryon(k) + eh.f_c * eh.cdm(k)
+}
+
+/// CDM part of `eisenstein_hu`, k in h/Mpc.
+pub fn eisenstein_hu_cdm(k: f64) -> f64 {
+    fit().cdm(k * H0)
+}
+
+/// Baryon part of `eisenstein_hu`, k in h/Mpc.
+pub fn eisenstein_hu_baryon(k: f64) -> f64 {
+    fit().baryon(k * H0)
+}
+
+/// Eisenstein & Hu (1998) transfer function without BAO, k in h/Mpc.
+pub fn eisenstein_hu_no_wiggle(k: f64) -> f64 {
+    fit().no_wiggle(k * H0)
+}
+
+/// T_WDM / T_CDM = (1 + (alpha k)^(2 nu))^(-5 / nu) of Viel et al. (2005), eq. 7, for a
+/// thermal relic of `mass` keV making up all of the dark matter, k in h/Mpc.
+pub fn wdm_suppression(k: f64, mass: f64) -> f64 {
+    let nu = 1.12;
+    let alpha = 0.049
+        * mass.powf(-1.11)
+        * ((OMEGA_M0 - OMEGA_B0) / 0.25).powf(0.11)
+        * (H0 / 0.7).powf(1.22);
+    (1. + (alpha * k).powf(2. * nu)).powf(-5. / nu)
+}
+
+/// BBKS (1986) transfer function, k in h/Mpc.
+pub fn bbks(k: f64) -> f64 {
+    let gamma = OMEGA_M0 * H0 * (-OMEGA_B0 - (2. * H0).sqrt() * OMEGA_B0 / OMEGA_M0).exp();
+    let q = k / gamma;
+    if q < 1e-8 {
+        return 1.;
+    }
+    (1. + 2.34 * q).ln() / (2.34 * q)
+        * (1. + 3.89 * q + (16.1 * q).powi(2) + (5.46 * q).powi(3) + (6.71 * q).powi(4)).powf(-0.25)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_large_scale_limit() {
+        for t in [eisenstein_hu, eisenstein_hu_no_wiggle, bbks] {
+            assert!((t(1e-5) - 1.).abs() < 1e-2, "{}", t(1e-5));
+            assert!(t(10.) < t(1.) && t(1.) < t(0.1));
+        }
+    }
+
+    #[test]
+    fn wiggles_oscillate_around_smooth_fit() {
+        let ks = (0..200).map(|i| 0.02 + 0.002 * i as f64);
+        let ratios: Vec<f64> = ks
+            .map(|k| eisenstein_hu(k) / eisenstein_hu_no_wiggle(k))
+            .collect();
+        assert!(ratios.iter().all(|r| (r - 1.).abs() < 0.1));
+        assert!(ratios.iter().any(|r| *r > 1.01));
+        assert!(ratios.iter().any(|r| *r < 0.99));
+    }
+
+    #[test]
+    fn species_transfers_sum_to_total() {
+        let f_b = OMEGA_B0 / OMEGA_M0;
+        for k in [1e-3, 0.05, 0.2, 1.5, 10.] {
+            let total = f_b * eisenstein_hu_baryon(k) + (1. - f_b) * eisenstein_hu_cdm(k);
+            assert!((total - eisenstein_hu(k)).abs() < 1e-12);
+        }
+        // baryons trail the CDM below the sound horizon
+        assert!(eisenstein_hu_baryon(1.) < eisenstein_hu_cdm(1.));
+    }
+
+    #[test]
+    fn wdm_truncates_small_scales() {
+        let cdm = PowerSpectrum::new(Transfer::EisensteinHu);
+        let wdm = cdm.with_wdm(1.);
+        assert!((wdm.power(0.01) / cdm.power(0.01) - 1.).abs() < 1e-3);
+        assert!(wdm.power(50.) < 1e-3 * cdm.power(50.));
+        // half-mode scale, T^2 = 1/2, moves to smaller scales for heavier particles
+        let half_mode = |m: f64| {
+            (0..4000)
+                .map(|i| 0.01 * 1.002f64.powi(i))
+                .find(|k| wdm_suppression(*k, m).powi(2) < 0.5)
+                .unwrap()
+        };
+        assert!(half_mode(3.) > half_mode(1.));
+    }
+
+    #[test]
+    fn sigma8_normalization() {
+        for transfer in [Transfer::EisensteinHu, Transfer::Bbks] {
+            let p = PowerSpectrum::new(transfer);
+            assert!((p.sigma(8.) - SIGMA_8).abs() < 1e-6);
+            assert!(p.sigma(4.) > p.sigma(8.) && p.sigma(8.) > p.sigma(16.));
+        }
+        let p = PowerSpectrum::new(Transfer::EisensteinHu);
+        let m8 = 4. / 3. * PI * 8f64.powi(3) * OMEGA_M0 * RHO_CRIT;
+        assert!((p.sigma_mass(m8) - SIGMA_8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn power_table_round_trip() {
+        let eh = PowerSpectrum::new(Transfer::EisensteinHu);
+        let k: Vec<f64> = (0..400)
+            .map(|i| 10f64.powf(-4. + 0.015 * i as f64))
+            .collect();
+        let pk: Vec<f64> = k.iter().map(|k| eh.power(*k)).collect();
+        let tabulated = PowerSpectrum::from_power_table(Table::new(&k, &pk).unwrap());
+        for k in [1e-3, 0.05, 0.2, 1.5] {
+            assert!((tabulated.power(k) / eh.power(k) - 1.).abs() < 1e-3);
+        }
+        assert!((tabulated.sigma(8.) / SIGMA_8 - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn power_law_matches_legacy_form() {
+        let p = PowerSpectrum::power_law(-0.845, 3.685);
+        assert!((p.power(2.) - 3.685 * 2f64.powf(-0.845)).abs() < 1e-12);
+        assert_eq!(p.power(0.), 0.);
+    }
+}
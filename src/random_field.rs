@@ -1,71 +1,314 @@
-use std::f64::INFINITY;
+use std::cmp::Ordering;
 
-use ndarray::{s, Array, Array3};
-use rand_distr::{num_traits::Pow, Distribution, Normal};
+use ndarray::{Array, Array3};
+use rand::{rngs::StdRng, SeedableRng};
+use rand_distr::{Distribution, Normal};
 use rustfft::num_complex::Complex64;
 
 use crate::{
-    config::{N_CELLS, N_PARTICLES},
+    config::{A_INIT, BOX_SIZE, FIXED_AMPLITUDE, F_NL, N_FIELD, OMEGA_M0, PAIRED, SEED},
+    cosmology::{growth_factor, matter_era_growth_factor, HUBBLE_DISTANCE},
     fourier::{forward, inverse, sample_freq},
     meshgrid::Meshgrid3,
+    power_spectrum::{fundamental_mode, PowerSpectrum},
 };
 
-pub fn gaussian_random_field(power: f64, amplitude: f64) -> Array3<f64> {
-    let gaussian_dist = Normal::new(0., 1.0).unwrap();
-    let mut seed =
-        Array::from_shape_simple_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), || Complex64 {
-            re: gaussian_dist.sample(&mut rand::thread_rng()),
-            im: 0.0,
-        });
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RandomFieldOptions {
+    pub seed: u64,
+    /// Cells per side of the field, independent of the particle count and force mesh.
+    pub grid: usize,
+    /// Fix every mode to the mean power |delta_k|^2 = P(k), keeping only random phases
+    /// (Angulo & Pontzen 2016).
+    pub fixed_amplitude: bool,
+    /// Shift every phase by pi, i.e. delta -> -delta, giving the partner of a pair.
+    pub paired: bool,
+    /// Local primordial non-Gaussianity, Phi = phi + f_nl (phi^2 - <phi^2>).
+    pub f_nl: f64,
+}
+
+impl Default for RandomFieldOptions {
+    fn default() -> Self {
+        RandomFieldOptions {
+            seed: SEED,
+            grid: N_FIELD,
+            fixed_amplitude: FIXED_AMPLITUDE,
+            paired: PAIRED,
+            f_nl: F_NL,
+        }
+    }
+}
+
+/// Draws a Gaussian random field on a `grid`^3 mesh spanning BOX_SIZE, with
+/// the given power spectrum evaluated at physical wavenumbers in h/Mpc and
+/// linearly evolved to A_INIT.
+pub fn gaussian_random_field(
+    spectrum: &PowerSpectrum,
+    options: &RandomFieldOptions,
+) -> Array3<f64> {
+    let n = options.grid;
+    let mut seed_k: Array3<Complex64> = white_noise_k(n, options.seed);
+    let n_modes = (n.pow(3) as f64).sqrt();
+    if options.fixed_amplitude {
+        seed_k.map_inplace(|x| *x = *x / x.norm() * n_modes);
+    }
+    if options.paired {
+        seed_k.map_inplace(|x| *x = -*x);
+    }
 
-    forward(&mut seed);
+    let knorms: Array3<f64> = wavenumbers(n);
 
-    let v_k = sample_freq(&N_PARTICLES)
+    // white noise has <|W_k|^2> = N^3, so scaling by sqrt(P / V_cell) gives
+    // <|delta_k|^2> = N^6 P(k) / V in the unnormalized DFT convention
+    let cell_volume = (BOX_SIZE as f64 / n as f64).powi(3);
+    // keeping the sign of T(k), which species transfer functions can flip
+    let power_spectrum_sqrt: Array3<f64> = knorms.map(|k| {
+        let sign = if spectrum.transfer(*k) < 0. { -1. } else { 1. };
+        sign * (spectrum.power(*k) / cell_volume).sqrt()
+    });
+
+    let mut realization_k: Array3<Complex64> = Array3::from_shape_vec(
+        (n, n, n),
+        Array::from_iter(seed_k.iter().zip(power_spectrum_sqrt).map(|(a, b)| a * b)).to_vec(),
+    )
+    .unwrap();
+    if options.f_nl != 0. {
+        assert!(
+            spectrum.transfer.is_transfer_function(),
+            "F_NL needs the potential, which a tabulated P(k) does not give; \
+             use TRANSFER or TRANSFER_FILE instead"
+        );
+        realization_k = local_non_gaussianity(realization_k, &knorms, spectrum, options.f_nl);
+    }
+    let realization_real: Array3<Complex64> = inverse(&realization_k);
+    let growth = growth_factor(A_INIT);
+    realization_real.map(|x| growth * x.re)
+}
+
+// |k| in h/Mpc of every mode of an n^3 grid spanning BOX_SIZE.
+fn wavenumbers(n: usize) -> Array3<f64> {
+    let v_k: Vec<f64> = sample_freq(&n)
         .iter()
-        .map(|x| (N_CELLS as f64) * x)
+        .map(|x| (n as f64) * fundamental_mode() * x)
         .collect();
-    let mut knorms: Array3<f64> = Meshgrid3::new(&v_k, &v_k, &v_k)
+    Meshgrid3::new(&v_k, &v_k, &v_k)
         .pow(2)
         .sum()
-        .map(|x| x.sqrt());
+        .map(|x| x.sqrt())
+}
 
-    if power > 0. {
-        knorms.slice_mut(s![0, 0, 0]).fill(INFINITY);
+// Signed integer frequency of DFT index i, matching `sample_freq`.
+fn frequency(i: usize, n: usize) -> i64 {
+    if i < n.div_ceil(2) {
+        i as i64
+    } else {
+        i as i64 - n as i64
     }
+}
 
-    if power < 3. {
-        let norms_real_mid = (N_PARTICLES as f64 / 2.).floor() as usize;
-        knorms
-            .slice_mut(s![norms_real_mid, norms_real_mid, norms_real_mid])
-            .fill(INFINITY);
-    }
+// SplitMix64 finalizer, so nearby wavevectors get unrelated streams.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
 
-    let power_spectrum: Array3<f64> = knorms.map(|x| amplitude * x.pow(-power));
-    let power_spectrum_sqrt: Array3<f64> = power_spectrum.map(|x| x.sqrt());
+fn deviate(seed: u64, f: [i64; 3]) -> Complex64 {
+    let key = f.iter().fold(mix(seed), |h, x| mix(h ^ (*x as u64)));
+    let mut rng = StdRng::seed_from_u64(key);
+    let gaussian_dist = Normal::new(0., 1.0).unwrap();
+    Complex64::new(
+        gaussian_dist.sample(&mut rng),
+        gaussian_dist.sample(&mut rng),
+    ) / 2f64.sqrt()
+}
 
-    let realization_k: Array3<Complex64> = Array3::from_shape_vec(
-        (N_PARTICLES, N_PARTICLES, N_PARTICLES),
-        Array::from_iter(seed.iter().zip(power_spectrum_sqrt).map(|(a, b)| a * b)).to_vec(),
-    )
-    .unwrap();
-    let realization_real: Array3<Complex64> = inverse(&realization_k);
-    realization_real.map(|x| x.re)
+/// Fourier transform of unit white noise on an n^3 grid, <|W_k|^2> = n^3. Every mode
+/// is drawn from a stream keyed by `seed` and its integer wavevector, so grids of
+/// different size share the same large-scale modes and only add or drop the small
+/// ones. The partner -k gets the complex conjugate and self-conjugate (zero and
+/// Nyquist) modes are made real.
+fn white_noise_k(n: usize, seed: u64) -> Array3<Complex64> {
+    let amplitude = (n.pow(3) as f64).sqrt();
+    Array3::from_shape_fn((n, n, n), |(i, j, k)| {
+        let f = [i, j, k].map(|x| frequency(x, n));
+        let partner = [i, j, k].map(|x| frequency((n - x) % n, n));
+        let g = match f.cmp(&partner) {
+            Ordering::Greater => deviate(seed, f),
+            Ordering::Less => deviate(seed, partner).conj(),
+            Ordering::Equal => Complex64::new(deviate(seed, f).re * 2f64.sqrt(), 0.),
+        };
+        g * amplitude
+    })
+}
+
+/// Poisson equation in the matter era, delta(k) = M(k) Phi(k), at a = 1.
+fn potential_to_density(spectrum: &PowerSpectrum, k: f64) -> f64 {
+    2. / 3. * (k * HUBBLE_DISTANCE).powi(2) * spectrum.transfer(k) * matter_era_growth_factor(1.)
+        / OMEGA_M0
+}
+
+fn local_non_gaussianity(
+    density_k: Array3<Complex64>,
+    knorms: &Array3<f64>,
+    spectrum: &PowerSpectrum,
+    f_nl: f64,
+) -> Array3<Complex64> {
+    let transfer: Array3<f64> = knorms.map(|k| potential_to_density(spectrum, *k));
+    let mut phi_k: Array3<Complex64> = density_k;
+    // dividing by M(k) of either sign, as species transfers cross zero
+    phi_k.zip_mut_with(&transfer, |phi, m| {
+        *phi = if *m != 0. {
+            *phi / *m
+        } else {
+            Complex64::new(0., 0.)
+        }
+    });
+
+    let phi: Array3<f64> = inverse(&phi_k).map(|x| x.re);
+    let phi_sq_mean = phi.map(|x| x * x).mean().unwrap();
+    let phi_nl: Array3<Complex64> =
+        phi.map(|x| Complex64::new(x + f_nl * (x * x - phi_sq_mean), 0.));
+
+    let mut density_k: Array3<Complex64> = forward(&phi_nl);
+    density_k.zip_mut_with(&transfer, |delta, m| *delta *= *m);
+    density_k
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::utils::array_3_to_image;
+    use crate::{power_spectrum::Transfer, utils::array_3_to_image};
+    use ndarray::s;
 
     #[test]
     fn generate_random_field() {
-        let amp = 3.685;
-        let power = 0.845;
+        let spectrum = PowerSpectrum::power_law(-0.845, 3.685);
 
+        let field = gaussian_random_field(&spectrum, &RandomFieldOptions::default());
         let image = array_3_to_image(
-            gaussian_random_field(power, amp).map(|x| (*x * 100.) as u8),
-            None,
+            field.slice(s![.., .., 0..3]).map(|x| (*x * 100.) as u8),
+            Some(N_FIELD),
         );
-        image.save("./out.png");
+        let _ = image.save("./out.png");
+    }
+
+    #[test]
+    fn paired_fixed_fields() {
+        let spectrum = PowerSpectrum::power_law(-0.845, 3.685);
+        let options = RandomFieldOptions {
+            seed: 7,
+            grid: N_FIELD,
+            fixed_amplitude: true,
+            paired: false,
+            f_nl: 0.,
+        };
+        let field = gaussian_random_field(&spectrum, &options);
+        assert_eq!(field, gaussian_random_field(&spectrum, &options));
+
+        let partner = gaussian_random_field(
+            &spectrum,
+            &RandomFieldOptions {
+                paired: true,
+                ..options
+            },
+        );
+        field
+            .iter()
+            .zip(partner.iter())
+            .for_each(|(a, b)| assert!((a + b).abs() < 1e-12));
+
+        // every mode carries exactly the mean power
+        let v_k: Vec<f64> = sample_freq(&N_FIELD)
+            .iter()
+            .map(|x| (N_FIELD as f64) * fundamental_mode() * x)
+            .collect();
+        let knorms = Meshgrid3::new(&v_k, &v_k, &v_k)
+            .pow(2)
+            .sum()
+            .map(|x| x.sqrt());
+        let cell_volume = (BOX_SIZE as f64 / N_FIELD as f64).powi(3);
+        let field_k = forward(&field.map(|x| Complex64::new(*x, 0.)));
+        field_k.iter().zip(knorms.iter()).for_each(|(d, k)| {
+            let expected =
+                N_FIELD.pow(3) as f64 * growth_factor(A_INIT).powi(2) * spectrum.power(*k)
+                    / cell_volume;
+            assert!((d.norm_sqr() - expected).abs() <= 1e-8 * expected.max(1e-8));
+        });
+    }
+
+    #[test]
+    fn white_noise_is_consistent_across_resolutions() {
+        let (coarse, fine) = (white_noise_k(8, 11), white_noise_k(16, 11));
+        for ((i, j, k), w) in coarse.indexed_iter() {
+            let f = [i, j, k].map(|x| frequency(x, 8));
+            if f.iter().any(|x| x.abs() == 4) {
+                continue;
+            }
+            let index = f.map(|x| x.rem_euclid(16) as usize);
+            let a = w / 8f64.powf(1.5);
+            let b = fine[index] / 16f64.powf(1.5);
+            assert!((a - b).norm() < 1e-12);
+        }
+
+        // Hermitian symmetry gives a real field with unit variance
+        let noise = inverse(&fine);
+        assert!(noise.iter().all(|x| x.im.abs() < 1e-10));
+        let variance = noise.map(|x| x.re * x.re).mean().unwrap();
+        assert!((variance - 1.).abs() < 0.1, "{}", variance);
+    }
+
+    #[test]
+    fn local_f_nl_is_quadratic_in_potential() {
+        let spectrum = PowerSpectrum::new(Transfer::EisensteinHu);
+        let field = |f_nl: f64| {
+            let options = RandomFieldOptions {
+                seed: 3,
+                grid: N_FIELD,
+                fixed_amplitude: false,
+                paired: false,
+                f_nl,
+            };
+            gaussian_random_field(&spectrum, &options)
+        };
+        let gaussian = field(0.);
+        let (single, double) = (field(1e4) - &gaussian, field(2e4) - &gaussian);
+        assert!(single.iter().any(|x| x.abs() > 1e-8));
+        single
+            .iter()
+            .zip(double.iter())
+            .for_each(|(a, b)| assert!((2. * a - b).abs() < 1e-6 * b.abs().max(1e-10)));
+
+        let skewness = |x: &Array3<f64>| {
+            let mean = x.mean().unwrap();
+            x.map(|v| (v - mean).powi(3)).mean().unwrap()
+        };
+        assert!(skewness(&field(1e5)) > skewness(&gaussian));
+    }
+
+    #[test]
+    #[should_panic(expected = "F_NL needs the potential")]
+    fn f_nl_rejects_tabulated_power() {
+        let k = [1e-4, 1., 1e3];
+        let table = crate::tabulated::Table::new(&k, &k.map(|k| k / (1. + k * k))).unwrap();
+        let options = RandomFieldOptions {
+            f_nl: 10.,
+            ..RandomFieldOptions::default()
+        };
+        gaussian_random_field(&PowerSpectrum::from_power_table(table), &options);
+    }
+
+    #[test]
+    fn zero_f_nl_keeps_sign_changing_transfers() {
+        let spectrum = PowerSpectrum::new(Transfer::EisensteinHuBaryon);
+        let knorms = wavenumbers(N_FIELD);
+        assert!(knorms.iter().any(|k| spectrum.transfer(*k) < 0.));
+        let field = gaussian_random_field(&spectrum, &RandomFieldOptions::default());
+        let density_k = forward(&field.map(|x| Complex64::new(*x, 0.)));
+        let scale = density_k.iter().map(|x| x.norm()).fold(0., f64::max);
+        let kept = local_non_gaussianity(density_k.clone(), &knorms, &spectrum, 0.);
+        kept.iter()
+            .zip(density_k.iter())
+            .for_each(|(a, b)| assert!((a - b).norm() < 1e-10 * scale));
     }
 }
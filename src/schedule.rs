@@ -0,0 +1,105 @@
+/// Time of a snapshot, given either way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputTime {
+    Redshift(f64),
+    ScaleFactor(f64),
+}
+
+impl OutputTime {
+    pub fn scale_factor(self) -> f64 {
+        match self {
+            OutputTime::Redshift(z) => 1. / (1. + z),
+            OutputTime::ScaleFactor(a) => a,
+        }
+    }
+}
+
+/// Outputs still to be written, in increasing scale factor, and the end of the run.
+/// Steps are shortened so that the integrator lands exactly on each of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pending: Vec<f64>,
+    end: f64,
+}
+
+impl Schedule {
+    /// Outputs after `a`, e.g. A_INIT or the time of a restart, up to `end`.
+    pub fn new(times: &[OutputTime], a: f64, end: f64) -> Schedule {
+        let mut pending: Vec<f64> = times
+            .iter()
+            .map(|t| t.scale_factor())
+            .filter(|t| *t > a && *t <= end)
+            .collect();
+        pending.sort_by(f64::total_cmp);
+        pending.dedup();
+        Schedule { pending, end }
+    }
+
+    pub fn finished(&self, a: f64) -> bool {
+        a >= self.end
+    }
+
+    /// Scale factor a step of `dt` from `a` ends on: the next output or the end of the
+    /// run when within reach, stretching the step by up to 0.1% rather than leaving a
+    /// sliver for the next one.
+    pub fn next_time(&self, a: f64, dt: f64) -> f64 {
+        let stop = self.pending.first().map_or(self.end, |t| t.min(self.end));
+        if stop - a <= 1.001 * dt {
+            stop
+        } else {
+            a + dt
+        }
+    }
+
+    /// Whether `a` is the next output, which is then taken off the schedule.
+    pub fn output_due(&mut self, a: f64) -> bool {
+        if self.pending.first() == Some(&a) {
+            self.pending.remove(0);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Base name of the snapshot files written at scale factor `a`.
+pub fn snapshot_name(a: f64) -> String {
+    format!("snapshot_z{:.3}", 1. / a - 1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_land_on_outputs() {
+        let times = [
+            OutputTime::Redshift(0.),
+            OutputTime::ScaleFactor(0.255),
+            OutputTime::Redshift(3.),
+            OutputTime::ScaleFactor(2.),
+            OutputTime::ScaleFactor(0.05),
+        ];
+        let mut schedule = Schedule::new(&times, 0.1, 1.);
+        let (mut a, dt) = (0.1, 0.02);
+        let mut outputs = vec![];
+        let mut steps = 0;
+        while !schedule.finished(a) {
+            let next = schedule.next_time(a, dt);
+            assert!(next > a && next - a <= 1.001 * dt);
+            a = next;
+            steps += 1;
+            if schedule.output_due(a) {
+                outputs.push(a);
+            }
+        }
+        assert_eq!(outputs, [0.25, 0.255, 1.]);
+        assert_eq!(a, 1.);
+        assert_eq!(steps, 47);
+
+        // a restart at an output doesn't write it again
+        let schedule = Schedule::new(&times, 0.25, 1.);
+        assert_eq!(schedule.pending, [0.255, 1.]);
+        assert_eq!(snapshot_name(0.25), "snapshot_z3.000");
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    f64::consts::PI,
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use rustfft::num_complex::Complex;
+
+use crate::{
+    healpix::{ang2pix, npix, pix2ang_ring, ring, vec2pix, Ordering},
+    lightcone::Crossings,
+};
+
+/// HEALPix map of the sky, one value per pixel in `ordering`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkyMap {
+    pub nside: usize,
+    pub ordering: Ordering,
+    pub pixels: Vec<f64>,
+}
+
+impl SkyMap {
+    pub fn new(nside: usize, ordering: Ordering) -> SkyMap {
+        SkyMap {
+            nside,
+            ordering,
+            pixels: vec![0.; npix(nside)],
+        }
+    }
+
+    /// Adds `weight` to the pixel in the direction of `v`.
+    pub fn add(&mut self, v: [f64; 3], weight: f64) {
+        self.pixels[vec2pix(self.nside, self.ordering, v)] += weight;
+    }
+
+    /// Contrast to the mean over the sky, zero for an empty map.
+    pub fn overdensity(&self) -> SkyMap {
+        let mean = self.pixels.iter().sum::<f64>() / self.pixels.len() as f64;
+        let pixels = if mean == 0. {
+            vec![0.; self.pixels.len()]
+        } else {
+            self.pixels.iter().map(|x| x / mean - 1.).collect()
+        };
+        SkyMap { pixels, ..*self }
+    }
+
+    /// The same map in the ring scheme.
+    pub fn to_ring(&self) -> SkyMap {
+        if self.ordering == Ordering::Ring {
+            return self.clone();
+        }
+        // each ring pixel centre lies inside exactly one nested pixel
+        let pixels = (0..self.pixels.len())
+            .map(|p| {
+                let (theta, phi) = pix2ang_ring(self.nside, p);
+                self.pixels[ang2pix(self.nside, self.ordering, theta, phi)]
+            })
+            .collect();
+        SkyMap {
+            nside: self.nside,
+            ordering: Ordering::Ring,
+            pixels,
+        }
+    }
+
+    /// Angular power spectrum C_l for l up to `l_max`, from the harmonic coefficients
+    /// a_lm summed directly over the pixels ring by ring: a Fourier sum in longitude
+    /// along each ring, then the orthonormal associated Legendre functions at its
+    /// latitude. Reliable up to l of about 3 nside.
+    pub fn angular_power_spectrum(&self, l_max: usize) -> Vec<f64> {
+        let map = self.to_ring();
+        let omega = 4. * PI / map.pixels.len() as f64;
+        let mut a_lm = vec![vec![Complex::new(0., 0.); l_max + 1]; l_max + 1];
+        let mut lambda = vec![0.; l_max + 1];
+        for i in 1..4 * map.nside {
+            let ring = ring(map.nside, i);
+            let values = &map.pixels[ring.start..ring.start + ring.len];
+            for m in 0..=l_max {
+                let f_m: Complex<f64> = values
+                    .iter()
+                    .enumerate()
+                    .map(|(j, x)| {
+                        let phi = ring.phi_0 + 2. * PI * j as f64 / ring.len as f64;
+                        Complex::from_polar(*x, -(m as f64) * phi)
+                    })
+                    .sum();
+                legendre(m, ring.z, &mut lambda);
+                for (a_l, lambda_l) in a_lm[m..].iter_mut().zip(&lambda[m..]) {
+                    a_l[m] += f_m * lambda_l * omega;
+                }
+            }
+        }
+        // a_l,-m = (-1)^m conj(a_lm) for a real map
+        a_lm.iter()
+            .enumerate()
+            .map(|(l, a)| {
+                let sum = a[0].norm_sqr() + 2. * a[1..=l].iter().map(|a| a.norm_sqr()).sum::<f64>();
+                sum / (2 * l + 1) as f64
+            })
+            .collect()
+    }
+}
+
+// orthonormal lambda_lm(z) = sqrt((2l+1)/4pi (l-m)!/(l+m)!) P_lm(z) into lambda[m..]
+fn legendre(m: usize, z: f64, lambda: &mut [f64]) {
+    let sin = (1. - z * z).max(0.).sqrt();
+    let mut mm = (0.25 / PI).sqrt();
+    for k in 1..=m {
+        mm *= -sin * ((2 * k + 1) as f64 / (2 * k) as f64).sqrt();
+    }
+    lambda[m] = mm;
+    if m + 1 < lambda.len() {
+        lambda[m + 1] = z * ((2 * m + 3) as f64).sqrt() * mm;
+    }
+    for l in m + 2..lambda.len() {
+        let (l2, m2) = ((l * l) as f64, (m * m) as f64);
+        let prev = ((l - 1) * (l - 1)) as f64;
+        lambda[l] = ((4. * l2 - 1.) / (l2 - m2)).sqrt()
+            * (z * lambda[l - 1] - ((prev - m2) / (4. * prev - 1.)).sqrt() * lambda[l - 2]);
+    }
+}
+
+/// Mass on the lightcone binned on the sky of its observer in redshift shells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shells {
+    pub observer: [f64; 3],
+    /// Increasing redshift edges, one more than the maps
+    pub edges: Vec<f64>,
+    pub maps: Vec<SkyMap>,
+}
+
+impl Shells {
+    pub fn new(observer: [f64; 3], edges: &[f64], nside: usize, ordering: Ordering) -> Shells {
+        Shells {
+            observer,
+            edges: edges.to_vec(),
+            maps: vec![SkyMap::new(nside, ordering); edges.len().saturating_sub(1)],
+        }
+    }
+
+    /// Adds the mass of each crossing to the shell of its redshift, ignoring those
+    /// outside every shell.
+    pub fn add(&mut self, crossings: &Crossings) {
+        let particles = &crossings.particles;
+        for (c, a) in crossings.scale_factors.iter().enumerate() {
+            let z = 1. / a - 1.;
+            let Some(shell) = self.edges.windows(2).position(|e| e[0] <= z && z < e[1]) else {
+                continue;
+            };
+            let v = [0, 1, 2].map(|i| particles.positions[[i, c]] - self.observer[i]);
+            self.maps[shell].add(v, particles.masses[c]);
+        }
+    }
+
+    pub fn redshifts(&self, shell: usize) -> (f64, f64) {
+        (self.edges[shell], self.edges[shell + 1])
+    }
+}
+
+const BLOCK: usize = 2880;
+
+// one 80 character header record
+fn card(key: &str, value: &str) -> String {
+    format!("{:<8}= {:<70}", key, value)
+}
+
+fn string(value: &str) -> String {
+    format!("'{:<8}'", value)
+}
+
+/// A map as a FITS primary image of big-endian doubles with the HEALPix keywords
+/// PIXTYPE, ORDERING and NSIDE, and the redshifts of its shell as ZMIN and ZMAX.
+pub fn write_fits(path: impl AsRef<Path>, map: &SkyMap, redshifts: (f64, f64)) -> Result<()> {
+    let ordering = match map.ordering {
+        Ordering::Ring => "RING",
+        Ordering::Nested => "NESTED",
+    };
+    let cards = [
+        card("SIMPLE", &format!("{:>20}", "T")),
+        card("BITPIX", &format!("{:>20}", -64)),
+        card("NAXIS", &format!("{:>20}", 1)),
+        card("NAXIS1", &format!("{:>20}", map.pixels.len())),
+        card("PIXTYPE", &string("HEALPIX")),
+        card("ORDERING", &string(ordering)),
+        card("NSIDE", &format!("{:>20}", map.nside)),
+        card("ZMIN", &format!("{:>20.12E}", redshifts.0)),
+        card("ZMAX", &format!("{:>20.12E}", redshifts.1)),
+        format!("{:<80}", "END"),
+    ];
+    let mut out = cards.concat().into_bytes();
+    out.resize(out.len().next_multiple_of(BLOCK), b' ');
+    out.extend(map.pixels.iter().flat_map(|x| x.to_be_bytes()));
+    out.resize(out.len().next_multiple_of(BLOCK), 0);
+    fs::write(path, out)
+}
+
+/// A map and shell redshifts written by `write_fits`.
+pub fn read_fits(path: impl AsRef<Path>) -> Result<(SkyMap, (f64, f64))> {
+    let bytes = fs::read(path)?;
+    let mut keys = vec![];
+    let mut data = None;
+    for (i, record) in bytes.chunks_exact(80).enumerate() {
+        let record = std::str::from_utf8(record).map_err(|_| invalid("header is not ASCII"))?;
+        let key = record[..8].trim_end();
+        if key == "END" {
+            data = Some(((i + 1) * 80).next_multiple_of(BLOCK));
+            break;
+        }
+        if record[8..].starts_with("= ") {
+            let value = record[10..].split('/').next().unwrap_or("");
+            keys.push((key, value.trim().trim_matches('\'').trim()));
+        }
+    }
+    let data = data.ok_or_else(|| invalid("missing END"))?;
+    let value = |key: &str| {
+        keys.iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+            .ok_or_else(|| invalid(&format!("missing {}", key)))
+    };
+    let number = |key: &str| -> Result<f64> {
+        value(key)?
+            .parse()
+            .map_err(|_| invalid(&format!("bad {}", key)))
+    };
+    if value("BITPIX")? != "-64" || value("PIXTYPE")? != "HEALPIX" {
+        return Err(invalid("not a HEALPix map of doubles"));
+    }
+    let ordering = match value("ORDERING")? {
+        "RING" => Ordering::Ring,
+        "NESTED" => Ordering::Nested,
+        _ => return Err(invalid("unknown ORDERING")),
+    };
+    let nside = number("NSIDE")? as usize;
+    let n = npix(nside);
+    if number("NAXIS1")? as usize != n || bytes.len() < data + 8 * n {
+        return Err(invalid("pixel count does not match NSIDE"));
+    }
+    let pixels = bytes[data..data + 8 * n]
+        .chunks_exact(8)
+        .map(|b| f64::from_be_bytes(b.try_into().unwrap()))
+        .collect();
+    let map = SkyMap {
+        nside,
+        ordering,
+        pixels,
+    };
+    Ok((map, (number("ZMIN")?, number("ZMAX")?)))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(nside: usize, ordering: Ordering, f: impl Fn(f64, f64) -> f64) -> SkyMap {
+        let mut map = SkyMap::new(nside, Ordering::Ring);
+        for (p, x) in map.pixels.iter_mut().enumerate() {
+            let (theta, phi) = pix2ang_ring(nside, p);
+            *x = f(theta, phi);
+        }
+        if ordering == Ordering::Nested {
+            let mut nested = SkyMap::new(nside, ordering);
+            for p in 0..npix(nside) {
+                let (theta, phi) = pix2ang_ring(nside, p);
+                nested.pixels[ang2pix(nside, ordering, theta, phi)] = map.pixels[p];
+            }
+            return nested;
+        }
+        map
+    }
+
+    #[test]
+    fn spectrum_of_spherical_harmonics() {
+        // Y_20, and the real part of Y_21 with a_21 = -a_2,-1 = 1/2
+        let y_20 = |theta: f64, _| (5. / (16. * PI)).sqrt() * (3. * theta.cos().powi(2) - 1.);
+        let y_21 = |theta: f64, phi: f64| {
+            -(15. / (8. * PI)).sqrt() * theta.sin() * theta.cos() * phi.cos()
+        };
+        for (f, c_2) in [(&y_20 as &dyn Fn(f64, f64) -> f64, 0.2), (&y_21, 0.1)] {
+            for ordering in [Ordering::Ring, Ordering::Nested] {
+                let c_l = map(16, ordering, f).angular_power_spectrum(4);
+                assert!((c_l[2] / c_2 - 1.).abs() < 1e-2);
+                for l in [0, 1, 3, 4] {
+                    assert!(c_l[l] < 1e-4 * c_2);
+                }
+            }
+        }
+
+        // a uniform sky is all monopole, and has no contrast
+        let uniform = map(4, Ordering::Ring, |_, _| 3.);
+        assert!((uniform.angular_power_spectrum(0)[0] - 4. * PI * 9.).abs() < 1e-9);
+        assert!(uniform.overdensity().pixels.iter().all(|x| x.abs() < 1e-12));
+    }
+
+    #[test]
+    fn fits_round_trip() {
+        let sky = map(4, Ordering::Nested, |theta, phi| theta * phi);
+        let path = std::env::temp_dir().join("nbody_test_sky.fits");
+        write_fits(&path, &sky, (0.1, 0.25)).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let read = read_fits(&path);
+        let _ = fs::remove_file(path);
+
+        assert_eq!(bytes.len() % BLOCK, 0);
+        assert!(bytes.starts_with(b"SIMPLE  =                    T"));
+        assert_eq!(read.unwrap(), (sky, (0.1, 0.25)));
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+/// A positive function of k sampled on a grid, interpolated linearly in log-log space
+/// and extrapolated with the power law of the outermost segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    ln_k: Vec<f64>,
+    ln_v: Vec<f64>,
+}
+
+impl Table {
+    pub fn new(k: &[f64], v: &[f64]) -> Result<Table> {
+        if k.len() != v.len() || k.len() < 2 {
+            return Err(invalid("table needs at least two (k, value) rows"));
+        }
+        if k.iter().chain(v).any(|x| x.is_nan() || *x <= 0.) {
+            return Err(invalid(
+                "table entries must be positive for log interpolation",
+            ));
+        }
+        if k.windows(2).any(|w| w[1] <= w[0]) {
+            return Err(invalid("k must be strictly increasing"));
+        }
+        Ok(Table {
+            ln_k: k.iter().map(|x| x.ln()).collect(),
+            ln_v: v.iter().map(|x| x.ln()).collect(),
+        })
+    }
+
+    pub fn eval(&self, k: f64) -> f64 {
+        let x = k.ln();
+        let n = self.ln_k.len();
+        let i = self.ln_k.partition_point(|ln_k| *ln_k <= x).clamp(1, n - 1);
+        let slope = (self.ln_v[i] - self.ln_v[i - 1]) / (self.ln_k[i] - self.ln_k[i - 1]);
+        (self.ln_v[i - 1] + slope * (x - self.ln_k[i - 1])).exp()
+    }
+
+    pub fn map(&self, f: impl Fn(f64) -> f64) -> Table {
+        Table {
+            ln_k: self.ln_k.clone(),
+            ln_v: self.ln_v.iter().map(|x| f(x.exp()).ln()).collect(),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Whitespace separated numeric columns, skipping blank and '#' comment lines.
+fn read_columns(path: impl AsRef<Path>, k_col: usize, v_col: usize) -> Result<Table> {
+    let contents = fs::read_to_string(path)?;
+    let (mut k, mut v) = (vec![], vec![]);
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let row: Vec<f64> = line
+            .split_whitespace()
+            .map(|x| x.parse::<f64>().map_err(|e| invalid(&e.to_string())))
+            .collect::<Result<_>>()?;
+        if row.len() <= k_col.max(v_col) {
+            return Err(invalid(&format!(
+                "expected {} columns: {}",
+                v_col + 1,
+                line
+            )));
+        }
+        k.push(row[k_col]);
+        v.push(row[v_col]);
+    }
+    Table::new(&k, &v)
+}
+
+/// CAMB `*_matterpower.dat`: k in h/Mpc, P(k) in (Mpc/h)^3.
+pub fn read_camb_matterpower(path: impl AsRef<Path>) -> Result<Table> {
+    read_columns(path, 0, 1)
+}
+
+/// CLASS `*_pk.dat`: k in h/Mpc, P(k) in (Mpc/h)^3.
+pub fn read_class_pk(path: impl AsRef<Path>) -> Result<Table> {
+    read_columns(path, 0, 1)
+}
+
+/// CAMB `*_transfer_out.dat` total matter column, normalized to unity at the smallest k.
+pub fn read_camb_transfer(path: impl AsRef<Path>) -> Result<Table> {
+    let table = read_columns(path, 0, 6)?;
+    let t0 = table.ln_v[0].exp();
+    Ok(table.map(|t| t / t0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_log_interpolation() {
+        let k = [0.1, 1., 10.];
+        let table = Table::new(&k, &k.map(|x| 3. * x.powi(2))).unwrap();
+        for k in [0.01, 0.3, 2., 50.] {
+            assert!((table.eval(k) / (3. * k * k) - 1.).abs() < 1e-10);
+        }
+        assert!(Table::new(&[1., 2.], &[1., 0.]).is_err());
+    }
+
+    #[test]
+    fn read_boltzmann_outputs() {
+        let dir = std::env::temp_dir();
+        let pk = dir.join("nbody_test_pk.dat");
+        fs::write(
+            &pk,
+            "# Matter power spectrum P(k) at redshift z=0\n#    k (h/Mpc)     P (Mpc/h)^3\n\
+             1.0e-4 4.0e2\n1.0e-2 2.0e4\n1.0e0 1.0e2\n",
+        )
+        .unwrap();
+        let table = read_class_pk(&pk).unwrap();
+        assert!((table.eval(1e-2) - 2e4).abs() < 1e-8);
+        assert_eq!(read_camb_matterpower(&pk).unwrap(), table);
+
+        let tk = dir.join("nbody_test_transfer_out.dat");
+        fs::write(
+            &tk,
+            " 1.0e-4 9 9 9 9 9 2.0e5\n 1.0e-1 9 9 9 9 9 1.0e5\n 1.0e1 9 9 9 9 9 1.0e2\n",
+        )
+        .unwrap();
+        let table = read_camb_transfer(&tk).unwrap();
+        assert!((table.eval(1e-4) - 1.).abs() < 1e-12);
+        assert!((table.eval(1e-1) - 0.5).abs() < 1e-12);
+        let _ = fs::remove_file(pk);
+        let _ = fs::remove_file(tk);
+    }
+}
@@ -1,5 +1,5 @@
 use image::{ImageBuffer, RgbImage};
-use ndarray::{s, Array, Array2, Array3, Axis};
+use ndarray::{s, Array2, Array3, Axis};
 
 use crate::config::{IMG_WIDTH, N_CELLS, N_PARTICLES};
 
@@ -16,7 +16,7 @@ pub fn array_3_to_image(
     .unwrap()
 }
 
-pub fn array_2_to_image(arr: Array2<f64>, width: usize) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+pub fn array_2_to_image(arr: Array2<f64>, _width: usize) -> ImageBuffer<image::Rgb<u8>, Vec<u8>> {
     let mut a: Array3<f64> = Array3::zeros((IMG_WIDTH, IMG_WIDTH, 3));
     let len = arr.len_of(Axis(1));
 
@@ -35,8 +35,8 @@ pub fn array_2_to_image(arr: Array2<f64>, width: usize) -> ImageBuffer<image::Rg
 use plotters::prelude::*;
 pub fn hist(arr: Vec<u32>, title: Option<String>) {
     let title = title.unwrap_or_else(|| String::from("histogram"));
-    let OUT_FILE_NAME = &format!("img/{}.png", title);
-    let root = BitMapBackend::new(OUT_FILE_NAME, (640, 480)).into_drawing_area();
+    let out_file_name = &format!("img/{}.png", title);
+    let root = BitMapBackend::new(out_file_name, (640, 480)).into_drawing_area();
 
     root.fill(&WHITE).unwrap();
 
@@ -46,7 +46,7 @@ pub fn hist(arr: Vec<u32>, title: Option<String>) {
         .margin(5)
         .caption(title, ("sans-serif", 50.0))
         .build_cartesian_2d(
-            (0u32..(50)).into_segmented(),
+            (0u32..50).into_segmented(),
             0u32..(2 * N_PARTICLES.pow(2) as u32),
         )
         .unwrap();
@@ -54,7 +54,7 @@ pub fn hist(arr: Vec<u32>, title: Option<String>) {
     chart
         .configure_mesh()
         .disable_x_mesh()
-        .bold_line_style(&WHITE.mix(0.3))
+        .bold_line_style(WHITE.mix(0.3))
         .y_desc("Count")
         .x_desc("Bucket")
         .axis_desc_style(("sans-serif", 15))
@@ -70,7 +70,7 @@ pub fn hist(arr: Vec<u32>, title: Option<String>) {
         .unwrap();
 
     root.present().expect("Unable to write result to file, please make sure 'plotters-doc-data' dir exists under current dir");
-    println!("Result has been saved to {}", OUT_FILE_NAME);
+    println!("Result has been saved to {}", out_file_name);
 }
 
 #[cfg(test)]
@@ -0,0 +1,261 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use ndarray::Array3;
+
+use crate::{
+    config::{BOX_SIZE, N_CELLS},
+    particles::Particles,
+};
+
+// Mpc/h per cell
+fn cell_size() -> f64 {
+    BOX_SIZE as f64 / N_CELLS as f64
+}
+
+/// XML VTK file whose arrays follow the markup as raw little-endian bytes, each
+/// preceded by its length as a UInt64.
+struct VtkFile {
+    xml: String,
+    appended: Vec<u8>,
+}
+
+impl VtkFile {
+    fn new(kind: &str, attributes: &str) -> VtkFile {
+        VtkFile {
+            xml: format!(
+                "<?xml version=\"1.0\"?>\n<VTKFile type=\"{kind}\" version=\"1.0\" \
+                 byte_order=\"LittleEndian\" header_type=\"UInt64\">\n  <{kind}{attributes}>\n"
+            ),
+            appended: vec![],
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.xml.push_str(line);
+        self.xml.push('\n');
+    }
+
+    fn array(&mut self, name: &str, kind: &str, components: usize, bytes: &[u8]) {
+        self.line(&format!(
+            "        <DataArray type=\"{}\" Name=\"{}\" NumberOfComponents=\"{}\" \
+             format=\"appended\" offset=\"{}\"/>",
+            kind,
+            name,
+            components,
+            self.appended.len()
+        ));
+        self.appended
+            .extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        self.appended.extend_from_slice(bytes);
+    }
+
+    fn write(mut self, kind: &str, path: impl AsRef<Path>) -> Result<()> {
+        self.line(&format!("  </{}>", kind));
+        self.line("  <AppendedData encoding=\"raw\">");
+        let mut out = self.xml.into_bytes();
+        out.push(b'_');
+        out.extend_from_slice(&self.appended);
+        out.extend_from_slice(b"\n  </AppendedData>\n</VTKFile>\n");
+        fs::write(path, out)
+    }
+}
+
+fn bytes<T, const N: usize>(values: impl IntoIterator<Item = T>, f: fn(T) -> [u8; N]) -> Vec<u8> {
+    values.into_iter().flat_map(f).collect()
+}
+
+/// Particle snapshot at scale factor `a` as VTK PolyData, one vertex per particle at
+/// its comoving position in Mpc/h, with the peculiar velocity in km/s, ID, mass and
+/// `ParticleType` code as point data.
+pub fn write_vtp(path: impl AsRef<Path>, particles: &Particles, a: f64) -> Result<()> {
+    let n = particles.len();
+    let mut file = VtkFile::new("PolyData", "");
+    file.line(&format!(
+        "    <Piece NumberOfPoints=\"{}\" NumberOfVerts=\"{}\">",
+        n, n
+    ));
+
+    let columns = |x: &ndarray::Array2<f64>, scale: f64| {
+        let values = (0..n).flat_map(|p| (0..3).map(move |i| x[[i, p]] * scale));
+        bytes(values, f64::to_le_bytes)
+    };
+    file.line("      <Points>");
+    file.array(
+        "Points",
+        "Float64",
+        3,
+        &columns(&particles.positions, cell_size()),
+    );
+    file.line("      </Points>");
+
+    // p = a^2 dx/dt with time in 1/H0, so 100 km/s per Mpc/h
+    let velocity_unit = 100. * cell_size() / a;
+    file.line("      <PointData Vectors=\"velocity\" Scalars=\"mass\">");
+    file.array(
+        "velocity",
+        "Float64",
+        3,
+        &columns(&particles.velocities, velocity_unit),
+    );
+    file.array(
+        "id",
+        "UInt64",
+        1,
+        &bytes(particles.ids.iter().copied(), u64::to_le_bytes),
+    );
+    file.array(
+        "mass",
+        "Float64",
+        1,
+        &bytes(particles.masses.iter().copied(), f64::to_le_bytes),
+    );
+    file.array(
+        "type",
+        "UInt8",
+        1,
+        &bytes(particles.types.iter().map(|t| t.code()), u8::to_le_bytes),
+    );
+    file.line("      </PointData>");
+
+    file.line("      <Verts>");
+    file.array(
+        "connectivity",
+        "Int64",
+        1,
+        &bytes(0..n as i64, i64::to_le_bytes),
+    );
+    file.array(
+        "offsets",
+        "Int64",
+        1,
+        &bytes(1..=n as i64, i64::to_le_bytes),
+    );
+    file.line("      </Verts>");
+    file.line("    </Piece>");
+    file.write("PolyData", path)
+}
+
+/// Mesh fields, e.g. density and potential, as VTK ImageData on the grid nodes,
+/// spaced by the cell size in Mpc/h. Grids are indexed [z, y, x] like the force
+/// mesh, unlike the x, y, z rows of the particle arrays written by `write_vtp`.
+pub fn write_vti(path: impl AsRef<Path>, grids: &[(&str, &Array3<f64>)]) -> Result<()> {
+    let Some((nz, ny, nx)) = grids
+        .first()
+        .map(|(_, grid)| grid.dim())
+        .filter(|(nz, ny, nx)| nz * ny * nx > 0)
+    else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "ImageData needs at least one non-empty grid",
+        ));
+    };
+    let extent = format!("0 {} 0 {} 0 {}", nx - 1, ny - 1, nz - 1);
+    let mut file = VtkFile::new(
+        "ImageData",
+        &format!(
+            " WholeExtent=\"{}\" Origin=\"0 0 0\" Spacing=\"{1} {1} {1}\"",
+            extent,
+            cell_size()
+        ),
+    );
+    file.line(&format!("    <Piece Extent=\"{}\">", extent));
+    file.line(&format!(
+        "      <PointData Scalars=\"{}\">",
+        grids.first().map_or("", |(name, _)| *name)
+    ));
+    for (name, grid) in grids {
+        assert_eq!(grid.dim(), (nz, ny, nx), "grids must share a shape");
+        // grid[[z, y, x]] in standard order already runs over x fastest, as VTK wants
+        file.array(
+            name,
+            "Float64",
+            1,
+            &bytes(grid.iter().copied(), f64::to_le_bytes),
+        );
+    }
+    file.line("      </PointData>");
+    file.line("    </Piece>");
+    file.write("ImageData", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::particles::ParticleType;
+    use ndarray::array;
+
+    // arrays of the appended section, in order
+    fn appended(file: &[u8]) -> Vec<Vec<u8>> {
+        let marker = b"encoding=\"raw\">\n_";
+        let mut at = file
+            .windows(marker.len())
+            .position(|w| w == marker)
+            .unwrap()
+            + marker.len();
+        let mut arrays = vec![];
+        while file[at..].len() > 8 && !file[at..].starts_with(b"\n  </AppendedData>") {
+            let len = u64::from_le_bytes(file[at..at + 8].try_into().unwrap()) as usize;
+            arrays.push(file[at + 8..at + 8 + len].to_vec());
+            at += 8 + len;
+        }
+        assert_eq!(&file[at..], b"\n  </AppendedData>\n</VTKFile>\n");
+        arrays
+    }
+
+    fn f64s(bytes: &[u8]) -> Vec<f64> {
+        bytes
+            .chunks(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn particles_as_poly_data() {
+        let particles = Particles::new(
+            array![[1., 2.], [3., 4.], [5., 6.]],
+            array![[0.5, 0.], [0., 0.], [0., -1.]],
+            1.,
+            ParticleType::DarkMatter,
+        );
+        let path = std::env::temp_dir().join("nbody_test_particles.vtp");
+        write_vtp(&path, &particles, 0.5).unwrap();
+        let file = fs::read(&path).unwrap();
+        let _ = fs::remove_file(path);
+
+        let xml = String::from_utf8_lossy(&file);
+        assert!(xml.contains("NumberOfPoints=\"2\""));
+        let arrays = appended(&file);
+        assert_eq!(arrays.len(), 7);
+        let points = f64s(&arrays[0]);
+        assert_eq!(points.len(), 6);
+        assert!((points[1] - 3. * cell_size()).abs() < 1e-12);
+        let velocity = f64s(&arrays[1]);
+        assert!((velocity[0] - 100. * cell_size()).abs() < 1e-12);
+        assert!((velocity[5] + 200. * cell_size()).abs() < 1e-12);
+        assert_eq!(arrays[2], [0u64.to_le_bytes(), 1u64.to_le_bytes()].concat());
+        assert_eq!(arrays[4], vec![0, 0]);
+    }
+
+    #[test]
+    fn grids_as_image_data() {
+        let density = Array3::from_shape_fn((2, 3, 4), |(z, y, x)| (100 * z + 10 * y + x) as f64);
+        let potential = -density.clone();
+        let path = std::env::temp_dir().join("nbody_test_grids.vti");
+        write_vti(&path, &[("density", &density), ("potential", &potential)]).unwrap();
+        let file = fs::read(&path).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert!(String::from_utf8_lossy(&file).contains("WholeExtent=\"0 3 0 2 0 1\""));
+        let arrays = appended(&file);
+        let values = f64s(&arrays[0]);
+        // x runs fastest
+        assert_eq!(values[..5], [0., 1., 2., 3., 10.]);
+        assert_eq!(f64s(&arrays[1])[23], -123.);
+
+        assert!(write_vti(std::env::temp_dir().join("nbody_test_empty.vti"), &[]).is_err());
+    }
+}
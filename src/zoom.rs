@@ -0,0 +1,323 @@
+use ndarray::{Array1, Array2};
+
+use crate::{
+    config::{N_CELLS, N_PARTICLES},
+    ic::{pre_initial_positions, ParticleLoad},
+    particles::{ParticleType, Particles},
+};
+
+/// Periodic box in Lagrangian (initial lattice) coordinates, in cell units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZoomRegion {
+    pub centre: [f64; 3],
+    pub half_width: [f64; 3],
+}
+
+impl ZoomRegion {
+    // Chebyshev distance in cells from the box, zero inside.
+    fn distance(&self, q: [f64; 3]) -> f64 {
+        (0..3)
+            .map(|i| (wrap(q[i] - self.centre[i]).abs() - self.half_width[i]).max(0.))
+            .fold(0., f64::max)
+    }
+}
+
+fn wrap(dx: f64) -> f64 {
+    let n = N_CELLS as f64;
+    (dx + n / 2.).rem_euclid(n) - n / 2.
+}
+
+/// Lagrangian position in cells of particle `p` of an `n`^3 lattice, laid out as
+/// `ic::pre_initial_positions` does with z running fastest.
+fn lattice_site(p: usize, n: usize) -> [f64; 3] {
+    let spacing = N_CELLS as f64 / n as f64;
+    [p / (n * n), p / n % n, p % n].map(|i| 0.5 + i as f64 * spacing)
+}
+
+/// Lagrangian region of the particles within `radius` cells of `centre` in a parent
+/// run on a `parent_lattice`^3 lattice, which may be coarser than N_PARTICLES. Sites
+/// come from the particle IDs, so the parent may have been sorted since; particles
+/// of other species, with IDs past the lattice, are skipped.
+pub fn lagrangian_region(
+    parent: &Particles,
+    parent_lattice: usize,
+    centre: [f64; 3],
+    radius: f64,
+) -> ZoomRegion {
+    let sites = parent_lattice.pow(3) as u64;
+    let selected: Vec<[f64; 3]> = parent
+        .positions
+        .columns()
+        .into_iter()
+        .zip(&parent.ids)
+        .filter(|(x, id)| {
+            **id < sites
+                && (0..3).map(|i| wrap(x[i] - centre[i]).powi(2)).sum::<f64>() <= radius.powi(2)
+        })
+        .map(|(_, id)| lattice_site(*id as usize, parent_lattice))
+        .collect();
+    assert!(
+        !selected.is_empty(),
+        "no parent particles within {} cells",
+        radius
+    );
+
+    // unwrap around the first member so the box may straddle the periodic boundary
+    let reference = selected[0];
+    let mut lower = [f64::INFINITY; 3];
+    let mut upper = [f64::NEG_INFINITY; 3];
+    for site in &selected {
+        for i in 0..3 {
+            let q = reference[i] + wrap(site[i] - reference[i]);
+            lower[i] = lower[i].min(q);
+            upper[i] = upper[i].max(q);
+        }
+    }
+    ZoomRegion {
+        centre: [0, 1, 2].map(|i| ((lower[i] + upper[i]) / 2.).rem_euclid(N_CELLS as f64)),
+        half_width: [0, 1, 2].map(|i| (upper[i] - lower[i]) / 2.),
+    }
+}
+
+/// Degrades full-resolution lattice ICs outside `region` into nested shells of
+/// heavier particles, each `shell_width` cells thick, merging aligned blocks of
+/// 2^l lattice particles per side at level l <= `levels`. All levels come from the
+/// same white noise, so the coarse particles carry the block averages of the
+/// displacements and velocities of the fine ones.
+///
+/// Nothing is finer than the N_PARTICLES^3 lattice: the region keeps the base
+/// resolution and only its surroundings are coarsened, so the whole box must still
+/// be generated at the resolution wanted in the region.
+pub fn zoom(
+    particles: &Particles,
+    load: ParticleLoad,
+    region: &ZoomRegion,
+    levels: usize,
+    shell_width: f64,
+) -> Particles {
+    assert_eq!(load, ParticleLoad::Lattice, "zoom needs lattice ICs");
+    let lattice: Array2<f64> = pre_initial_positions(ParticleLoad::Lattice);
+    assert!(
+        lattice.dim() == particles.positions.dim()
+            && particles
+                .ids
+                .iter()
+                .enumerate()
+                .all(|(p, id)| *id == p as u64),
+        "zoom needs the lattice ICs in lattice order"
+    );
+    let level_of: Vec<usize> = lattice
+        .columns()
+        .into_iter()
+        .map(|q| {
+            let d = region.distance([q[0], q[1], q[2]]);
+            ((d / shell_width).ceil() as usize).min(levels)
+        })
+        .collect();
+
+    let mut zoomed = Zoomed::default();
+    let size = 1 << levels;
+    for i in (0..N_PARTICLES).step_by(size) {
+        for j in (0..N_PARTICLES).step_by(size) {
+            for k in (0..N_PARTICLES).step_by(size) {
+                zoomed.block([i, j, k], levels, &level_of, &lattice, particles);
+            }
+        }
+    }
+
+    let n = zoomed.masses.len();
+    let to_array = |x: Vec<[f64; 3]>| {
+        Array2::from_shape_vec((n, 3), x.concat())
+            .unwrap()
+            .reversed_axes()
+            .as_standard_layout()
+            .to_owned()
+    };
+    Particles {
+        ids: Array1::from(zoomed.ids),
+        positions: to_array(zoomed.positions),
+        velocities: to_array(zoomed.velocities),
+        masses: Array1::from(zoomed.masses),
+        types: zoomed.types,
+    }
+}
+
+#[derive(Default)]
+struct Zoomed {
+    ids: Vec<u64>,
+    positions: Vec<[f64; 3]>,
+    velocities: Vec<[f64; 3]>,
+    masses: Vec<f64>,
+    types: Vec<ParticleType>,
+}
+
+impl Zoomed {
+    fn block(
+        &mut self,
+        origin: [usize; 3],
+        level: usize,
+        level_of: &[usize],
+        lattice: &Array2<f64>,
+        particles: &Particles,
+    ) {
+        let size = 1 << level;
+        let members: Vec<usize> = (origin[0]..(origin[0] + size).min(N_PARTICLES))
+            .flat_map(|i| {
+                (origin[1]..(origin[1] + size).min(N_PARTICLES)).flat_map(move |j| {
+                    (origin[2]..(origin[2] + size).min(N_PARTICLES))
+                        .map(move |k| (i * N_PARTICLES + j) * N_PARTICLES + k)
+                })
+            })
+            .collect();
+        if members.is_empty() {
+            return;
+        }
+
+        if level > 0 && members.iter().any(|p| level_of[*p] < level) {
+            let half = size / 2;
+            for offset in 0..8 {
+                let child = [0, 1, 2].map(|a| origin[a] + ((offset >> (2 - a)) & 1) * half);
+                self.block(child, level - 1, level_of, lattice, particles);
+            }
+            return;
+        }
+
+        let (positions, velocities) = (&particles.positions, &particles.velocities);
+        if level == 0 {
+            let p = members[0];
+            self.ids.push(particles.ids[p]);
+            self.positions.push([0, 1, 2].map(|a| positions[[a, p]]));
+            self.velocities.push([0, 1, 2].map(|a| velocities[[a, p]]));
+            self.masses.push(particles.masses[p]);
+            self.types.push(particles.types[p]);
+            return;
+        }
+
+        // lattice blocks are contiguous, so only displacements need unwrapping; the
+        // merged particle inherits the ID of the block's first member
+        self.ids.push(particles.ids[members[0]]);
+        let mass: f64 = members.iter().map(|p| particles.masses[*p]).sum();
+        let mean = |f: &dyn Fn(usize, usize) -> f64| -> [f64; 3] {
+            [0, 1, 2].map(|a| {
+                members
+                    .iter()
+                    .map(|p| particles.masses[*p] * f(a, *p))
+                    .sum::<f64>()
+                    / mass
+            })
+        };
+        let q = mean(&|a, p| lattice[[a, p]]);
+        let psi = mean(&|a, p| wrap(positions[[a, p]] - lattice[[a, p]]));
+        self.positions
+            .push([0, 1, 2].map(|a| (q[a] + psi[a]).rem_euclid(N_CELLS as f64)));
+        self.velocities.push(mean(&|a, p| velocities[[a, p]]));
+        self.masses.push(mass);
+        self.types.push(ParticleType::Boundary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        curve::SpaceFillingCurve,
+        ic::{initial_conditions, LptOrder},
+    };
+    use ndarray::{s, Array3, Axis};
+
+    #[test]
+    fn region_from_parent_wraps_periodically() {
+        let lattice = pre_initial_positions(ParticleLoad::Lattice);
+        for (p, q) in lattice.columns().into_iter().enumerate() {
+            let site = lattice_site(p, N_PARTICLES);
+            assert!((0..3).all(|i| (site[i] - q[i]).abs() < 1e-12));
+        }
+        let parent = Particles::new(
+            lattice.clone(),
+            Array2::zeros(lattice.dim()),
+            1.,
+            ParticleType::DarkMatter,
+        );
+        let region = lagrangian_region(&parent, N_PARTICLES, [0.5, 8.5, 8.5], 2.);
+        assert!(region.distance([N_CELLS as f64 - 1.1, 8.5, 8.5]) == 0.);
+        assert!(region.distance([0.5, 8.5, 8.5]) == 0.);
+        assert!(region.distance([8.5, 8.5, 8.5]) > 0.);
+        assert!(region.half_width.iter().all(|w| *w < 2.));
+    }
+
+    #[test]
+    fn region_from_coarser_sorted_parent() {
+        // a parent at half the resolution whose particles all moved a cell along x,
+        // then were sorted out of lattice order
+        let n = N_PARTICLES / 2;
+        let mut positions = Array2::from_shape_fn((3, n.pow(3)), |(i, p)| lattice_site(p, n)[i]);
+        positions
+            .row_mut(0)
+            .mapv_inplace(|x| (x + 1.).rem_euclid(N_CELLS as f64));
+        let mut parent = Particles::new(
+            positions.clone(),
+            Array2::zeros(positions.dim()),
+            1.,
+            ParticleType::DarkMatter,
+        );
+        parent.sort_along(SpaceFillingCurve::Hilbert);
+        let p = (2 * n + 2) * n + 2;
+        assert_ne!(parent.index_of(p as u64), Some(p));
+        let site = lattice_site(p, n);
+        let moved = [site[0] + 1., site[1], site[2]];
+
+        let region = lagrangian_region(&parent, n, moved, 0.5);
+        assert_eq!(region.centre, site);
+        assert_eq!(region.half_width, [0.; 3]);
+    }
+
+    #[test]
+    fn zoom_conserves_mass_and_keeps_high_resolution_region() {
+        let field = Array3::from_shape_fn((N_PARTICLES, N_PARTICLES, N_PARTICLES), |(i, j, k)| {
+            1e-3 * ((i + 2 * j + 3 * k) as f64).sin()
+        });
+        let mut particles = initial_conditions(field, LptOrder::First, ParticleLoad::Lattice);
+        particles.masses.fill(2.);
+        let region = ZoomRegion {
+            centre: [8., 8., 8.],
+            half_width: [1.7; 3],
+        };
+        let zoomed = zoom(&particles, ParticleLoad::Lattice, &region, 2, 2.);
+
+        let n = N_PARTICLES.pow(3);
+        assert!(zoomed.len() < n);
+        assert_eq!(zoomed.positions.len_of(Axis(1)), zoomed.len());
+        assert_eq!(zoomed.velocities.len_of(Axis(1)), zoomed.len());
+        assert!((zoomed.total_mass() - 2. * n as f64).abs() < 1e-9);
+        // blocks at the box edge are partial when 2^levels does not divide N_PARTICLES
+        assert!(zoomed.masses.iter().all(|m| m % 2. == 0. && *m <= 2. * 64.));
+        assert!(zoomed.mass_of(ParticleType::Boundary) > 0.);
+        let mut ids = zoomed.ids.to_vec();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), zoomed.len());
+
+        // every lattice particle inside the region survives untouched
+        let lattice = pre_initial_positions(ParticleLoad::Lattice);
+        for p in 0..n {
+            let q = lattice.slice(s![.., p]);
+            if region.distance([q[0], q[1], q[2]]) == 0. {
+                let q = zoomed.index_of(p as u64).unwrap();
+                assert_eq!(zoomed.positions.column(q), particles.positions.column(p));
+                assert_eq!(zoomed.types[q], ParticleType::DarkMatter);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "zoom needs lattice ICs")]
+    fn zoom_rejects_poisson_loads() {
+        let field = Array3::zeros((N_PARTICLES, N_PARTICLES, N_PARTICLES));
+        let particles = initial_conditions(field, LptOrder::First, ParticleLoad::Poisson);
+        let region = ZoomRegion {
+            centre: [8., 8., 8.],
+            half_width: [1.; 3],
+        };
+        zoom(&particles, ParticleLoad::Poisson, &region, 1, 2.);
+    }
+}